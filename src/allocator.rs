@@ -0,0 +1,248 @@
+//! First-fit free-list allocator for the VM heap.
+//!
+//! The allocator only keeps the block list, the bytes live in `VM::heap`.
+//! Blocks cover the heap from offset 0 without gaps. A request takes the
+//! first free block that is large enough, splitting off the rest, and grows
+//! the heap when none is. Freed blocks merge with free neighbours and free
+//! space at the end of the heap is given back by shrinking it.
+
+/// Sizes are rounded up to this so `LOADW`/`STOREW` stay word aligned.
+pub const ALIGNMENT: usize = 4;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Block {
+    /// Offset of the block in the heap.
+    pub offset: usize,
+    pub size: usize,
+    pub free: bool,
+}
+
+/// Totals reported by `Allocator::stats`.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub live_blocks: usize,
+    pub free_blocks: usize,
+    pub used_bytes: usize,
+    pub free_bytes: usize,
+    pub largest_free: usize,
+}
+
+impl HeapStats {
+    /// Share of the free bytes outside of the largest free block, 0 when the
+    /// free space is all in one piece and 1 when it is scattered.
+    pub fn fragmentation(&self) -> f64 {
+        match self.free_bytes {
+            0 => 0.0,
+            free => 1.0 - self.largest_free as f64 / free as f64,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Allocator {
+    blocks: Vec<Block>,
+}
+
+impl Allocator {
+    pub fn new() -> Allocator {
+        Allocator { blocks: vec![] }
+    }
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+    /// Allocates `size` zeroed bytes and returns their offset in `heap`, or
+    /// `None` when the heap would have to grow past `limit`.
+    pub fn allocate(&mut self, heap: &mut Vec<u8>, size: usize, limit: usize) -> Option<usize> {
+        let size = size.max(1).checked_next_multiple_of(ALIGNMENT)?;
+        let index = match self
+            .blocks
+            .iter()
+            .position(|block| block.free && block.size >= size)
+        {
+            Some(index) => index,
+            None => self.grow(heap, size, limit)?,
+        };
+        let block = self.blocks[index];
+        if block.size > size {
+            self.blocks.insert(
+                index + 1,
+                Block {
+                    offset: block.offset + size,
+                    size: block.size - size,
+                    free: true,
+                },
+            );
+        }
+        self.blocks[index] = Block {
+            offset: block.offset,
+            size,
+            free: false,
+        };
+        heap[block.offset..block.offset + size].fill(0);
+        Some(block.offset)
+    }
+    /// Releases the live block starting at `offset`. Returns `false` if
+    /// there is no such block.
+    pub fn free(&mut self, heap: &mut Vec<u8>, offset: usize) -> bool {
+        let Some(index) = self
+            .blocks
+            .iter()
+            .position(|block| block.offset == offset && !block.free)
+        else {
+            return false;
+        };
+        self.blocks[index].free = true;
+        if self.blocks.get(index + 1).is_some_and(|next| next.free) {
+            self.blocks[index].size += self.blocks.remove(index + 1).size;
+        }
+        if index > 0 && self.blocks[index - 1].free {
+            self.blocks[index - 1].size += self.blocks.remove(index).size;
+        }
+        if let Some(last) = self.blocks.last() {
+            if last.free && last.offset + last.size == heap.len() {
+                heap.truncate(last.offset);
+                self.blocks.pop();
+            }
+        }
+        true
+    }
+    /// Adds `size` bytes of free space at the end of the heap without
+    /// allocating them. Returns `false` when the heap would grow past `limit`.
+    pub fn reserve(&mut self, heap: &mut Vec<u8>, size: usize, limit: usize) -> bool {
+        let Some(size) = size.checked_next_multiple_of(ALIGNMENT) else {
+            return false;
+        };
+        if size == 0 {
+            return true;
+        }
+        let tail = match self.blocks.last() {
+            Some(last) if last.free => last.size,
+            _ => 0,
+        };
+        self.grow(heap, tail + size, limit).is_some()
+    }
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats::default();
+        for block in &self.blocks {
+            if block.free {
+                stats.free_blocks += 1;
+                stats.free_bytes += block.size;
+                stats.largest_free = stats.largest_free.max(block.size);
+            } else {
+                stats.live_blocks += 1;
+                stats.used_bytes += block.size;
+            }
+        }
+        stats
+    }
+    /// Makes room for `size` more bytes at the end of the heap, extending
+    /// the last block if it is free, and returns the index of the free block.
+    fn grow(&mut self, heap: &mut Vec<u8>, size: usize, limit: usize) -> Option<usize> {
+        let end = heap.len();
+        match self.blocks.last_mut() {
+            Some(last) if last.free && last.offset + last.size == end => {
+                let new_end = last.offset.checked_add(size)?;
+                if new_end > limit {
+                    return None;
+                }
+                last.size = size;
+                heap.resize(new_end, 0);
+            }
+            _ => {
+                let new_end = end.checked_add(size)?;
+                if new_end > limit {
+                    return None;
+                }
+                self.blocks.push(Block {
+                    offset: end,
+                    size,
+                    free: true,
+                });
+                heap.resize(new_end, 0);
+            }
+        }
+        Some(self.blocks.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_reuses_freed_blocks() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let a = allocator.allocate(&mut heap, 5, 1024).unwrap();
+        let b = allocator.allocate(&mut heap, 8, 1024).unwrap();
+        let c = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        assert_eq!((a, b, c), (0, 8, 16));
+        assert_eq!(heap.len(), 20);
+
+        heap[8] = 7;
+        assert!(allocator.free(&mut heap, b));
+        assert!(!allocator.free(&mut heap, b));
+        assert!(!allocator.free(&mut heap, 9));
+        assert_eq!(allocator.allocate(&mut heap, 3, 1024), Some(8));
+        assert_eq!(heap[8], 0);
+        assert_eq!(
+            allocator.blocks()[2],
+            Block {
+                offset: 12,
+                size: 4,
+                free: true
+            }
+        );
+        assert_eq!(allocator.stats().fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn test_free_coalesces_and_shrinks() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let blocks: Vec<usize> = (0..4)
+            .map(|_| allocator.allocate(&mut heap, 4, 1024).unwrap())
+            .collect();
+        allocator.free(&mut heap, blocks[0]);
+        allocator.free(&mut heap, blocks[2]);
+        let stats = allocator.stats();
+        assert_eq!((stats.live_blocks, stats.free_blocks), (2, 2));
+        assert_eq!((stats.used_bytes, stats.free_bytes), (8, 8));
+        assert_eq!(stats.fragmentation(), 0.5);
+
+        allocator.free(&mut heap, blocks[1]);
+        assert_eq!(allocator.stats().largest_free, 12);
+        allocator.free(&mut heap, blocks[3]);
+        assert!(heap.is_empty());
+        assert!(allocator.blocks().is_empty());
+    }
+
+    #[test]
+    fn test_allocate_respects_limit() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        assert_eq!(allocator.allocate(&mut heap, 12, 16), Some(0));
+        assert_eq!(allocator.allocate(&mut heap, 8, 16), None);
+        assert_eq!(allocator.allocate(&mut heap, usize::MAX, 16), None);
+        assert_eq!(allocator.allocate(&mut heap, 4, 16), Some(12));
+        allocator.free(&mut heap, 12);
+        allocator.free(&mut heap, 0);
+        assert_eq!(allocator.allocate(&mut heap, 16, 16), Some(0));
+    }
+    #[test]
+    fn test_reserve_adds_free_space() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        assert!(allocator.reserve(&mut heap, 6, 32));
+        assert!(allocator.reserve(&mut heap, 8, 32));
+        assert_eq!(heap.len(), 16);
+        assert_eq!(allocator.stats().free_blocks, 1);
+        assert_eq!(allocator.stats().live_blocks, 0);
+        assert_eq!(allocator.allocate(&mut heap, 16, 32), Some(0));
+        assert_eq!(heap.len(), 16);
+        assert!(allocator.reserve(&mut heap, 16, 32));
+        assert!(!allocator.reserve(&mut heap, 4, 32));
+        assert!(!allocator.reserve(&mut heap, usize::MAX, 32));
+        assert_eq!(heap.len(), 32);
+    }
+}
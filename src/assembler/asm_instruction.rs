@@ -1,8 +1,13 @@
-use crate::instruction::Opcode;
+use crate::instruction::{Opcode, OperandKind};
 
-use super::{helpers, symbol_table::SymbolTable, Token};
+use super::{
+    error::{AsmError, AsmErrorKind, Span},
+    helpers,
+    symbol_table::{Symbol, SymbolTable, SymbolType},
+    Token,
+};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Default)]
 pub struct AsmInstruction {
     pub opcode: Option<Token>,
     pub label: Option<Token>,
@@ -10,81 +15,259 @@ pub struct AsmInstruction {
     pub operand1: Option<Token>,
     pub operand2: Option<Token>,
     pub operand3: Option<Token>,
+    /// Source line the instruction was parsed from, starting at 1.
+    pub line: usize,
+    pub label_span: Span,
+    /// Span of the opcode or the directive.
+    pub head_span: Span,
+    pub operand_spans: [Span; 3],
 }
 
 impl AsmInstruction {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
-        let mut result: Vec<u8> = Vec::new();
-        if let Some(ref token) = self.opcode {
-            match token {
-                Token::Op { code } => match &code {
-                    Opcode::LOAD => {
-                        result.push(1);
-                        if let Some(ref token) = self.operand1 {
-                            match token {
-                                Token::Register { reg_num } => {
-                                    result.push(*reg_num);
-                                }
-                                _ => {}
-                            }
-                        }
-                        if let Some(ref token) = self.operand2 {
-                            match token {
-                                Token::IntegerOperand { value } => {
-                                    let results = helpers::parse_i32_to_vecu8(*value);
-                                    result.extend(results);
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    Opcode::HLT => result.push(0),
-                    Opcode::IGL => result.push(0),
-                    Opcode::ADD => result.push(2),
-                    Opcode::SUB => result.push(3),
-                    Opcode::MUL => result.push(4),
-                    Opcode::DIV => result.push(5),
-                    Opcode::JMP => result.push(6),
-                    Opcode::JMPF => result.push(7),
-                    Opcode::EQ => result.push(8),
-                    Opcode::NEQ => result.push(9),
-                    Opcode::GT => result.push(10),
-                    Opcode::LT => result.push(11),
-                    Opcode::GTQ => result.push(12),
-                    Opcode::LTQ => result.push(13),
-                    Opcode::JMPEQ => result.push(14),
-                    Opcode::LABEL => result.push(15),
-                    Opcode::SQUARE => result.push(16),
-                    Opcode::ALLOC => result.push(17),
-                    Opcode::SET => result.push(18),
-                },
-                _ => {}
+    pub fn error(&self, kind: AsmErrorKind, span: Span) -> AsmError {
+        AsmError::new(kind, self.line, span)
+    }
+    /// Span just past the last token, where a missing operand is reported.
+    fn end_span(&self) -> Span {
+        [self.label_span, self.head_span]
+            .iter()
+            .chain(&self.operand_spans)
+            .rfind(|span| span.len > 0)
+            .copied()
+            .unwrap_or_default()
+            .after()
+    }
+    /// Opcode the instruction is encoded with. `LOAD` of an immediate that
+    /// fits in 16 bits becomes `LOADS`, `JMP`, `JMPEQ` and `JMPNEQ` to a label
+    /// or an address become `JMPA`, `JMPEQA` and `JMPNEQA`.
+    pub fn encoded_opcode(&self) -> Option<Opcode> {
+        let code = match &self.opcode {
+            Some(Token::Op { code }) => *code,
+            _ => return None,
+        };
+        let code = match (code, &self.operand1, &self.operand2) {
+            (Opcode::LOAD, _, Some(Token::IntegerOperand { value }))
+                if helpers::fits_i16(*value) =>
+            {
+                Opcode::LOADS
             }
-        }
-        if let Some(ref token) = self.operand1 {
-            match token {
-                Token::Register { reg_num } => {
-                    result.push(*reg_num);
-                }
-                _ => {}
+            (Opcode::JMP, Some(Token::LabelUsage { .. } | Token::IntegerOperand { .. }), _) => {
+                Opcode::JMPA
+            }
+            (Opcode::JMPEQ, Some(Token::LabelUsage { .. } | Token::IntegerOperand { .. }), _) => {
+                Opcode::JMPEQA
             }
+            (Opcode::JMPNEQ, Some(Token::LabelUsage { .. } | Token::IntegerOperand { .. }), _) => {
+                Opcode::JMPNEQA
+            }
+            _ => code,
+        };
+        Some(code)
+    }
+    /// Bytes a `.asciiz` or `.integer` directive puts in the read-only
+    /// section: a NUL terminated string or a big-endian `i32`.
+    pub fn data_bytes(&self) -> Result<Vec<u8>, AsmError> {
+        let directive = match &self.directive {
+            Some(Token::Directive { name }) => name.as_str(),
+            _ => return Ok(vec![]),
+        };
+        if let Some(operand) = &self.operand2 {
+            return Err(self.error(
+                AsmErrorKind::ExtraOperand {
+                    head: if directive == "asciiz" {
+                        ".asciiz"
+                    } else {
+                        ".integer"
+                    },
+                    expected: 1,
+                    found: operand.describe().into(),
+                },
+                self.operand_spans[1],
+            ));
         }
-        if let Some(ref token) = self.operand2 {
-            match token {
-                Token::Register { reg_num } => {
-                    result.push(*reg_num);
-                }
-                _ => {}
+        match (directive, &self.operand1) {
+            ("asciiz", Some(Token::IrString { name })) => {
+                let mut bytes = name.as_bytes().to_vec();
+                bytes.push(0);
+                Ok(bytes)
+            }
+            ("integer", Some(Token::IntegerOperand { value })) if i32::try_from(*value).is_ok() => {
+                Ok(helpers::encode_i32(*value as i32).to_vec())
             }
+            (_, operand) => Err(self.error(
+                AsmErrorKind::DirectiveOperandMismatch {
+                    directive: directive.to_string(),
+                    found: operand
+                        .as_ref()
+                        .map_or("nothing".to_string(), |operand| operand.describe()),
+                },
+                match operand {
+                    Some(_) => self.operand_spans[0],
+                    None => self.end_span(),
+                },
+            )),
         }
-        if let Some(ref token) = self.operand3 {
-            match token {
-                Token::Register { reg_num } => {
-                    result.push(*reg_num);
+    }
+    /// Number of bytes `to_bytes` produces, known before labels are resolved.
+    pub fn size(&self) -> usize {
+        self.encoded_opcode().map_or(0, |code| code.info().size())
+    }
+    /// Encodes the instruction as its opcode byte followed by the operands
+    /// declared in the opcode table, checking each operand against its kind.
+    pub fn to_bytes(&self, symbols: &SymbolTable, natives: &[String]) -> Result<Vec<u8>, AsmError> {
+        let mut result: Vec<u8> = Vec::new();
+        let code = match self.encoded_opcode() {
+            Some(code) => code,
+            None => return Ok(result),
+        };
+        result.push(u8::from(code));
+
+        let schema = code.info().operands;
+        let operands = [&self.operand1, &self.operand2, &self.operand3];
+        for (index, operand) in operands.iter().enumerate() {
+            let position = index + 1;
+            let span = self.operand_spans[index];
+            let (expected, operand) = match (schema.get(index), operand) {
+                (Some(expected), Some(operand)) => (*expected, operand),
+                (Some(expected), None) => {
+                    return Err(self.error(
+                        AsmErrorKind::MissingOperand {
+                            opcode: code,
+                            position,
+                            expected: *expected,
+                        },
+                        self.end_span(),
+                    ))
+                }
+                (None, Some(operand)) => {
+                    // Named after what was written, `jmp` rather than `jmpa`.
+                    let written = match &self.opcode {
+                        Some(Token::Op { code: written }) => *written,
+                        _ => code,
+                    };
+                    return Err(self.error(
+                        AsmErrorKind::ExtraOperand {
+                            head: written.mnemonic(),
+                            expected: schema.len() as u8,
+                            found: operand.describe().into(),
+                        },
+                        span,
+                    ));
+                }
+                (None, None) => break,
+            };
+            let out_of_range = |value: i64| {
+                self.error(
+                    AsmErrorKind::ImmediateOutOfRange {
+                        opcode: code,
+                        position,
+                        expected,
+                        value,
+                    },
+                    span,
+                )
+            };
+            match (expected, operand) {
+                (OperandKind::Register, Token::Register { reg_num }) => result.push(*reg_num),
+                (OperandKind::FloatRegister, Token::FloatRegister { reg_num }) => {
+                    result.push(*reg_num)
+                }
+                (OperandKind::F64, Token::FloatOperand { value }) => {
+                    result.extend(helpers::encode_f64(*value))
+                }
+                (OperandKind::F64, Token::IntegerOperand { value }) => {
+                    result.extend(helpers::encode_f64(*value as f64))
+                }
+                (OperandKind::Imm16, Token::IntegerOperand { value }) => {
+                    let value = i16::try_from(*value).map_err(|_| out_of_range(*value))?;
+                    result.extend(helpers::encode_i16(value))
+                }
+                (OperandKind::Imm32, Token::IntegerOperand { value }) => {
+                    let value = i32::try_from(*value).map_err(|_| out_of_range(*value))?;
+                    result.extend(helpers::encode_i32(value))
+                }
+                (OperandKind::LongRegister, Token::LongRegister { reg_num }) => {
+                    result.push(*reg_num)
+                }
+                (OperandKind::Imm64, Token::IntegerOperand { value }) => {
+                    result.extend(helpers::encode_i64(*value))
+                }
+                (OperandKind::Native, Token::NativeUsage { name }) => {
+                    let index = natives
+                        .iter()
+                        .position(|native| native == name)
+                        .and_then(|index| u16::try_from(index).ok())
+                        .ok_or_else(|| {
+                            self.error(AsmErrorKind::UndefinedNative { name: name.clone() }, span)
+                        })?;
+                    result.extend(index.to_be_bytes())
+                }
+                (OperandKind::Native, Token::IntegerOperand { value }) => {
+                    let index = u16::try_from(*value).map_err(|_| out_of_range(*value))?;
+                    result.extend(index.to_be_bytes())
+                }
+                // LOAD takes the address of either kind of label, a data
+                // label for LOADS and LOADB or a code label for `jmp $reg`.
+                (OperandKind::Imm32, Token::LabelUsage { name }) => {
+                    let address = self.resolve(symbols, name, None, span)?;
+                    result.extend(helpers::encode_i32(address as i32))
+                }
+                (OperandKind::Address, Token::LabelUsage { name }) => {
+                    let address = self.resolve(symbols, name, Some(SymbolType::Label), span)?;
+                    result.extend(address.to_be_bytes())
+                }
+                (OperandKind::Address, Token::IntegerOperand { value }) => {
+                    let address = u32::try_from(*value).map_err(|_| out_of_range(*value))?;
+                    result.extend(address.to_be_bytes())
+                }
+                _ => {
+                    return Err(self.error(
+                        AsmErrorKind::OperandMismatch {
+                            opcode: code,
+                            position,
+                            expected,
+                            found: operand.describe(),
+                        },
+                        span,
+                    ))
                 }
-                _ => {}
             }
         }
-        result
+        Ok(result)
+    }
+    /// Address of the label `name`, which has to be of the `expected` type
+    /// unless that is `None`.
+    fn resolve(
+        &self,
+        symbols: &SymbolTable,
+        name: &str,
+        expected: Option<SymbolType>,
+        span: Span,
+    ) -> Result<u32, AsmError> {
+        let Some(
+            symbol @ Symbol {
+                offset: Some(offset),
+                ..
+            },
+        ) = symbols.symbol(name)
+        else {
+            return Err(self.error(
+                AsmErrorKind::UndefinedLabel {
+                    name: name.to_string(),
+                },
+                span,
+            ));
+        };
+        match expected {
+            Some(expected) if expected != symbol.symbol_type => Err(self.error(
+                AsmErrorKind::WrongLabelType {
+                    name: name.to_string(),
+                    expected,
+                    found: symbol.symbol_type,
+                },
+                span,
+            )),
+            _ => Ok(*offset),
+        }
     }
 }
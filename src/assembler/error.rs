@@ -0,0 +1,319 @@
+use std::fmt;
+
+use super::symbol_table::SymbolType;
+use super::Section;
+use crate::instruction::{Opcode, OperandKind, REGISTER_ALIASES, REGISTER_COUNT};
+
+/// Where a token sits in its source line, in characters from the start of
+/// the line.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub column: usize,
+    pub len: usize,
+}
+
+impl Span {
+    /// Span of `token`, a slice of `line`.
+    pub fn of(line: &str, token: &str) -> Span {
+        let start = token.as_ptr() as usize - line.as_ptr() as usize;
+        Span {
+            column: line[..start].chars().count(),
+            len: token.chars().count(),
+        }
+    }
+    /// Empty span right after this one, where a missing token would go.
+    pub fn after(self) -> Span {
+        Span {
+            column: self.column + self.len,
+            len: 0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AsmErrorKind {
+    /// Source text that none of the token parsers accept.
+    UnknownToken {
+        found: String,
+    },
+    UnknownMnemonic {
+        name: String,
+    },
+    BadRegister {
+        found: String,
+    },
+    /// A string literal without its closing quote.
+    UnterminatedString,
+    /// An integer literal that does not fit in an `i64`.
+    IntegerOutOfRange {
+        found: String,
+    },
+    /// A float literal too large for an `f64`.
+    FloatOutOfRange {
+        found: String,
+    },
+    /// An operand does not match the kind declared for it in the opcode table.
+    OperandMismatch {
+        opcode: Opcode,
+        position: usize,
+        expected: OperandKind,
+        found: String,
+    },
+    ImmediateOutOfRange {
+        opcode: Opcode,
+        position: usize,
+        expected: OperandKind,
+        value: i64,
+    },
+    MissingOperand {
+        opcode: Opcode,
+        position: usize,
+        expected: OperandKind,
+    },
+    UnknownDirective {
+        name: String,
+    },
+    /// `.asciiz` without a string or `.integer` without an integer.
+    DirectiveOperandMismatch {
+        directive: String,
+        found: String,
+    },
+    /// An instruction in the data section or data in the code section.
+    WrongSection {
+        found: String,
+        section: Section,
+    },
+    UndefinedLabel {
+        name: String,
+    },
+    DuplicateLabel {
+        name: String,
+    },
+    /// A label in the data section without a directive on its line.
+    BareDataLabel {
+        name: String,
+    },
+    /// A data label where a code label is expected, or the other way round.
+    WrongLabelType {
+        name: String,
+        expected: SymbolType,
+        found: SymbolType,
+    },
+    /// A `!name` operand for a native function the assembler was not told
+    /// about.
+    UndefinedNative {
+        name: String,
+    },
+    /// An operand that does not belong to any instruction.
+    UnexpectedOperand {
+        found: String,
+    },
+    /// More operands than the opcode or directive `head` takes.
+    ExtraOperand {
+        head: &'static str,
+        expected: u8,
+        found: Box<str>,
+    },
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmErrorKind::UnknownToken { found } => {
+                write!(f, "unrecognized token `{}`", found)
+            }
+            AsmErrorKind::UnknownMnemonic { name } => write!(f, "unknown mnemonic `{}`", name),
+            AsmErrorKind::BadRegister { found } => {
+                let aliases: Vec<String> = REGISTER_ALIASES
+                    .iter()
+                    .map(|(alias, _)| format!("`${}`", alias))
+                    .collect();
+                write!(
+                    f,
+                    "invalid register `{}`, expected `$0` to `${last}`, `$f0` to `$f{last}`, `$l0` to `$l{last}` or one of {}",
+                    found,
+                    aliases.join(", "),
+                    last = REGISTER_COUNT - 1
+                )
+            }
+            AsmErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            AsmErrorKind::IntegerOutOfRange { found } => {
+                write!(f, "integer `{}` does not fit in 64 bits", found)
+            }
+            AsmErrorKind::FloatOutOfRange { found } => {
+                write!(f, "float `{}` does not fit in an `f64`", found)
+            }
+            AsmErrorKind::OperandMismatch {
+                opcode,
+                position,
+                expected,
+                found,
+            } => write!(
+                f,
+                "operand {} of `{}` must be a {}, found {}",
+                position,
+                opcode.mnemonic(),
+                expected,
+                found
+            ),
+            AsmErrorKind::ImmediateOutOfRange {
+                opcode,
+                position,
+                expected,
+                value,
+            } => write!(
+                f,
+                "operand {} of `{}` must be a {}, {} is out of range",
+                position,
+                opcode.mnemonic(),
+                expected,
+                value
+            ),
+            AsmErrorKind::MissingOperand {
+                opcode,
+                position,
+                expected,
+            } => write!(
+                f,
+                "`{}` expects {} operands, operand {} ({}) is missing",
+                opcode.mnemonic(),
+                opcode.info().operands.len(),
+                position,
+                expected
+            ),
+            AsmErrorKind::UnknownDirective { name } => write!(f, "unknown directive `.{}`", name),
+            AsmErrorKind::DirectiveOperandMismatch { directive, found } => {
+                let expected = match directive.as_str() {
+                    "asciiz" => "a string",
+                    _ => "a 32-bit integer",
+                };
+                write!(f, "`.{}` expects {}, found {}", directive, expected, found)
+            }
+            AsmErrorKind::WrongSection { found, section } => {
+                write!(f, "{} is not allowed in the {} section", found, section)
+            }
+            AsmErrorKind::UndefinedLabel { name } => write!(f, "label `{}` is not defined", name),
+            AsmErrorKind::DuplicateLabel { name } => {
+                write!(f, "label `{}` is defined more than once", name)
+            }
+            AsmErrorKind::BareDataLabel { name } => write!(
+                f,
+                "label `{}` in `.data` has to share its line with `.asciiz` or `.integer`",
+                name
+            ),
+            AsmErrorKind::WrongLabelType {
+                name,
+                expected,
+                found,
+            } => write!(f, "`{}` is a {}, not a {}", name, found, expected),
+            AsmErrorKind::UndefinedNative { name } => {
+                write!(f, "native function `{}` is not registered", name)
+            }
+            AsmErrorKind::UnexpectedOperand { found } => {
+                write!(f, "unexpected {} outside of an instruction", found)
+            }
+            AsmErrorKind::ExtraOperand {
+                head,
+                expected,
+                found,
+            } => {
+                let takes = match expected {
+                    0 => "no operands".to_string(),
+                    1 => "1 operand".to_string(),
+                    count => format!("{} operands", count),
+                };
+                write!(f, "`{}` takes {}, found an extra {}", head, takes, found)
+            }
+        }
+    }
+}
+
+/// A diagnostic pointing at a token of the source. `Display` renders it the
+/// way rustc does, with the offending line and a caret under the token.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AsmError {
+    pub kind: AsmErrorKind,
+    /// Name of the source file, `None` for sources that did not come from one.
+    pub file: Option<String>,
+    /// Line of the token, starting at 1.
+    pub line: usize,
+    /// Column of the token in characters, starting at 1.
+    pub column: usize,
+    /// Length of the token in characters.
+    pub len: usize,
+    /// The whole line the token is on.
+    pub source_line: String,
+}
+
+impl AsmError {
+    /// Error at `span` of `line`. The file and source line are filled in by
+    /// `Assembler::assemble`, which is the one that knows them.
+    pub fn new(kind: AsmErrorKind, line: usize, span: Span) -> AsmError {
+        AsmError {
+            kind,
+            file: None,
+            line,
+            column: span.column + 1,
+            len: span.len,
+            source_line: String::new(),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        // Tabs are kept so the caret lines up however wide the terminal
+        // renders them.
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        writeln!(f, "error: {}", self.kind)?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter,
+            self.file.as_deref().unwrap_or("<input>"),
+            self.line,
+            self.column
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source_line)?;
+        write!(f, "{} | {}{}", gutter, indent, "^".repeat(self.len.max(1)))
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_points_at_token() {
+        let mut error = AsmError::new(
+            AsmErrorKind::UnknownMnemonic {
+                name: "addx".to_string(),
+            },
+            12,
+            Span { column: 6, len: 4 },
+        );
+        error.file = Some("prog.asm".to_string());
+        error.source_line = "loop:\taddx $1 $2".to_string();
+        assert_eq!(
+            error.to_string(),
+            "error: unknown mnemonic `addx`\n  --> prog.asm:12:7\n   |\n12 | loop:\taddx $1 $2\n   |      \t^^^^"
+        );
+    }
+
+    #[test]
+    fn test_span_counts_characters() {
+        let line = "żółw: load $1 7";
+        let token = &line[line.find("load").unwrap()..][..4];
+        assert_eq!(Span::of(line, token), Span { column: 6, len: 4 });
+        assert_eq!(Span::of(line, token).after(), Span { column: 10, len: 0 });
+    }
+}
@@ -1,64 +1,157 @@
-pub fn parse_i32_to_vecu8(value: i32) -> Vec<u8> {
-    let mut result = vec![];
-    if value > 16_777_215 {
-        let byte1 = (value & 0xFF) as u8; // Lowest 8 bits
-        let byte2 = ((value >> 8) & 0xFF) as u8; // Next 8 bits
-        let byte3 = ((value >> 16) & 0xFF) as u8; // Next 8 bits
-        let byte4 = ((value >> 24) & 0xFF) as u8; // Highest 8 bits
-        result.push(byte4);
-        result.push(byte3);
-        result.push(byte2);
-        result.push(byte1);
-    } else if value > 65_535 {
-        let byte1 = (value & 0xFF) as u8; // Lowest 8 bits
-        let byte2 = ((value >> 8) & 0xFF) as u8; // Next 8 bits
-        let byte3 = ((value >> 16) & 0xFF) as u8; // Highest 8 bits
-        result.push(0); // 1 padding byte for 4-byte alignment
-        result.push(byte3);
-        result.push(byte2);
-        result.push(byte1);
-    } else if value > 255 {
-        let low_byte = (value & 0xFF) as u8; // Lowest 8 bits
-        let high_byte = ((value >> 8) & 0xFF) as u8; // Highest 8 bits
-        result.push(0); // 2 padding bytes for 4-byte alignment
-        result.push(0);
-        result.push(high_byte);
-        result.push(low_byte);
-    } else if value > -16_777_215 {
-        let byte1 = (value & 0xFF) as u8; // Lowest 8 bits
-        let byte2 = ((value >> 8) & 0xFF) as u8; // Next 8 bits
-        let byte3 = ((value >> 16) & 0xFF) as u8; // Next 8 bits
-        let byte4 = ((value >> 24) & 0xFF) as u8; // Highest 8 bits
-        result.push(byte4);
-        result.push(byte3);
-        result.push(byte2);
-        result.push(byte1);
-    } else if value > -65_535 {
-        // 3 bytes
-        let byte1 = (value & 0xFF) as u8; // Lowest 8 bits
-        let byte2 = ((value >> 8) & 0xFF) as u8; // Next 8 bits
-        let byte3 = ((value >> 16) & 0xFF) as u8; // Highest 8 bits
-        result.push(1);
-        result.push(byte3);
-        result.push(byte2);
-        result.push(byte1);
-    } else if value > -255 {
-        let low_byte = (value & 0xFF) as u8; // Lowest 8 bits
-        let high_byte = ((value >> 8) & 0xFF) as u8; // Highest 8 bits
-        result.push(1); // 2 padding bytes for 4-byte alignment
-        result.push(1);
-        result.push(high_byte);
-        result.push(low_byte);
-    } else if value <= -1 {
-        result.push(1);
-        result.push(1);
-        result.push(1);
-        result.push(value as u8);
-    } else {
-        result.push(0); // 3 padding bytes for 4-byte alignment
-        result.push(0);
-        result.push(0);
-        result.push(value as u8);
+/// Immediates are stored big-endian in two's complement, the VM reads them
+/// back with `i16::from_be_bytes`, `i32::from_be_bytes` and
+/// `i64::from_be_bytes`.
+pub fn encode_i16(value: i16) -> [u8; 2] {
+    value.to_be_bytes()
+}
+
+pub fn encode_i32(value: i32) -> [u8; 4] {
+    value.to_be_bytes()
+}
+
+pub fn encode_i64(value: i64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
+/// Floats are stored as their IEEE 754 bits, so no precision is lost.
+pub fn encode_f64(value: f64) -> [u8; 8] {
+    value.to_bits().to_be_bytes()
+}
+
+pub fn fits_i16(value: i64) -> bool {
+    i16::try_from(value).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::instruction::Opcode;
+    use crate::vm::VM;
+
+    // Walks the whole i32 range with a stride that is coprime with 256 so
+    // every byte value shows up in every position, plus the edges.
+    fn sample_i32() -> impl Iterator<Item = i32> {
+        let edges = [
+            i32::MIN,
+            i32::MIN + 1,
+            -16_777_216,
+            -65_536,
+            -32_769,
+            -32_768,
+            -256,
+            -255,
+            -1,
+            0,
+            1,
+            255,
+            256,
+            32_767,
+            32_768,
+            65_535,
+            16_777_215,
+            i32::MAX - 1,
+            i32::MAX,
+        ];
+        (i32::MIN..=i32::MAX).step_by(65_537).chain(edges)
+    }
+
+    fn load(program: Vec<u8>) -> i32 {
+        let mut test_vm = VM::new();
+        test_vm.program = program;
+        test_vm.run().unwrap();
+        test_vm.registers[3]
+    }
+
+    #[test]
+    fn test_encode_i32_is_big_endian() {
+        assert_eq!(encode_i32(1025), [0, 0, 4, 1]);
+        assert_eq!(encode_i32(-1), [255, 255, 255, 255]);
+        assert_eq!(encode_i32(-256), [255, 255, 255, 0]);
+        assert_eq!(encode_i32(i32::MIN), [128, 0, 0, 0]);
+        assert_eq!(encode_i16(-300), [254, 212]);
+    }
+
+    #[test]
+    fn test_i32_round_trips_through_vm() {
+        for value in sample_i32() {
+            let mut program = vec![u8::from(Opcode::LOAD), 3];
+            program.extend(encode_i32(value));
+            assert_eq!(load(program), value, "LOAD of {}", value);
+        }
+    }
+
+    #[test]
+    fn test_i64_round_trips_through_vm() {
+        let edges = [i64::MIN, i64::MAX, 1 << 32, -(1 << 40) - 7, -1];
+        for value in sample_i32()
+            .map(|value| (value as i64) << 24 ^ value as i64)
+            .chain(edges)
+        {
+            let mut program = vec![u8::from(Opcode::LOADL), 3];
+            program.extend(encode_i64(value));
+            let mut test_vm = VM::new();
+            test_vm.program = program;
+            test_vm.run().unwrap();
+            assert_eq!(test_vm.long_registers[3], value, "LOADL of {}", value);
+        }
+        assert_eq!(encode_i64(-2), [255, 255, 255, 255, 255, 255, 255, 254]);
+    }
+
+    #[test]
+    fn test_i16_round_trips_through_vm() {
+        for value in i16::MIN..=i16::MAX {
+            let mut program = vec![u8::from(Opcode::LOADS), 3];
+            program.extend(encode_i16(value));
+            assert_eq!(load(program), value as i32, "LOADS of {}", value);
+        }
+    }
+
+    #[test]
+    fn test_f64_round_trips_through_assembler() {
+        let mut assembler = Assembler::new();
+        let values = [
+            0.0,
+            -0.0,
+            0.1,
+            -1.5,
+            1e300,
+            5e-324,
+            f64::MAX,
+            f64::MIN,
+            1.0 / 3.0,
+        ];
+        for value in values {
+            let program = assembler
+                .assemble(&format!("loadf $f2 {:?}", value))
+                .unwrap();
+            let mut test_vm = VM::new();
+            test_vm.program = program.code;
+            test_vm.run().unwrap();
+            assert_eq!(
+                test_vm.float_registers[2].to_bits(),
+                value.to_bits(),
+                "{:?}",
+                value
+            );
+        }
+        assert_eq!(encode_f64(1.0), [63, 240, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_fits_i16() {
+        for value in sample_i32() {
+            assert_eq!(fits_i16(value.into()), (-32_768..=32_767).contains(&value));
+        }
+        assert!(!fits_i16(i64::MIN) && !fits_i16(1 << 32));
+    }
+
+    #[test]
+    fn test_assembled_load_round_trips() {
+        let mut assembler = Assembler::new();
+        for value in sample_i32() {
+            let program = assembler.assemble(&format!("load $3 {}", value)).unwrap();
+            assert_eq!(load(program.code), value, "load $3 {}", value);
+        }
     }
-    return result;
 }
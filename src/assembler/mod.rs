@@ -1,312 +1,1193 @@
-mod asm_instruction;
-mod helpers;
-mod parsers;
-mod symbol_table;
-use std::{
-    borrow::{Borrow, BorrowMut},
-    ops::Deref,
-};
+pub mod asm_instruction;
+pub mod error;
+pub mod helpers;
+pub mod parsers;
+pub mod symbol_table;
+
+use std::fmt;
 
 use asm_instruction::AsmInstruction;
-use nom::{
-    branch::alt,
-    bytes::complete::{tag, tag_no_case, take_while1},
-    character::complete::{alpha1, alphanumeric1, char, digit1, multispace0, space0, space1},
-    combinator::{map, opt, recognize},
-    number::complete::{float, recognize_float},
-    sequence::{pair, preceded, terminated},
-    IResult,
-};
-use symbol_table::{Symbol, SymbolType};
+use error::{AsmError, AsmErrorKind, Span};
+use symbol_table::{Symbol, SymbolTable, SymbolType};
 
 use crate::instruction::Opcode;
 #[derive(Debug, PartialEq)]
 pub enum Token {
-    Op { code: Opcode },
-    Register { reg_num: u8 },
-    IntegerOperand { value: i32 },
-    FloatOperand { value: f32 },
-    LabelDeclaration { name: String },
-    LabelUsage { name: String },
-    Directive { name: String },
-    IrString { name: String },
+    Op {
+        code: Opcode,
+    },
+    Register {
+        reg_num: u8,
+    },
+    FloatRegister {
+        reg_num: u8,
+    },
+    LongRegister {
+        reg_num: u8,
+    },
+    /// Name of a native function, `!name` in the source.
+    NativeUsage {
+        name: String,
+    },
+    IntegerOperand {
+        value: i64,
+    },
+    FloatOperand {
+        value: f64,
+    },
+    LabelDeclaration {
+        name: String,
+    },
+    LabelUsage {
+        name: String,
+    },
+    Directive {
+        name: String,
+    },
+    IrString {
+        name: String,
+    },
     Comment,
 }
 
+impl Token {
+    /// Short human readable description used in error messages.
+    pub fn describe(&self) -> String {
+        match self {
+            Token::Op { code } => format!("opcode `{}`", code.mnemonic()),
+            Token::Register { reg_num } => format!("register `${}`", reg_num),
+            Token::IntegerOperand { value } => format!("integer `{}`", value),
+            Token::FloatRegister { reg_num } => format!("float register `$f{}`", reg_num),
+            Token::LongRegister { reg_num } => format!("long register `$l{}`", reg_num),
+            Token::NativeUsage { name } => format!("native `!{}`", name),
+            Token::FloatOperand { value } => format!("float `{:?}`", value),
+            Token::LabelDeclaration { name } => format!("label declaration `{}`", name),
+            Token::LabelUsage { name } => format!("label usage `{}`", name),
+            Token::Directive { name } => format!("directive `.{}`", name),
+            Token::IrString { name } => format!("string \"{}\"", name),
+            Token::Comment => "comment".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Section {
+    /// Instructions, the default until a `.data` directive is seen.
+    Code,
+    /// Read-only data declared with `.asciiz` and `.integer`.
+    Data,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::Code => write!(f, "`.code`"),
+            Section::Data => write!(f, "`.data`"),
+        }
+    }
+}
+
+/// Output of `Assembler::assemble`, ready to be loaded into a `VM`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    /// Offset in `code` execution starts at, the `main` label if there is one.
+    pub entry: u32,
+    pub code: Vec<u8>,
+    pub ro_data: Vec<u8>,
+    pub symbols: SymbolTable,
+}
+
 #[derive(Debug, Default)]
 pub struct Assembler {
     pub program: Vec<u8>,
     pub ro: Vec<u8>,
-    pub symbols: Vec<Symbol>,
+    pub symbols: SymbolTable,
+    /// Name of the file being assembled, shown in diagnostics.
+    pub file: Option<String>,
+    /// Native functions `!name` operands may refer to, in the order they
+    /// were registered on the VM, see `VM::native_names`.
+    pub natives: Vec<String>,
+    // Code and read-only data already loaded in front of the source being
+    // assembled, see `assemble_at`.
+    code_base: u32,
+    data_base: u32,
 }
 impl Assembler {
     pub fn new() -> Assembler {
         Assembler {
             program: vec![],
-            symbols: vec![],
+            symbols: SymbolTable::new(),
             ro: vec![],
+            file: None,
+            natives: vec![],
+            code_base: 0,
+            data_base: 0,
         }
     }
-    pub fn assemble<'a>(&self, raw: &'a str) -> Result<Vec<u8>, &str> {
-        let mut result: Vec<u8> = Vec::new();
-        let tokens = self.tokenize(raw).unwrap();
-
-        Ok(result)
+    /// Assembles a whole source file into a program image. On failure every
+    /// problem found is returned, ordered by position in the source. Errors
+    /// in the second pass are only looked for once the first one is clean.
+    pub fn assemble(&mut self, raw: &str) -> Result<Program, Vec<AsmError>> {
+        self.assemble_at(raw, 0, 0, SymbolTable::new())
     }
-    fn process_first_phase(&mut self, tokens: Vec<Token>) {
-        let mut index = 0;
-
-        while index < tokens.len() {
-            let token = &tokens[index];
-            match token {
-                Token::LabelDeclaration { name } => {
-                    println!("LabelDeclaration: {}", name);
-
-                    let symbol = Symbol::new(name.to_string(), SymbolType::Integer);
-                    self.symbols.push(symbol);
-                }
-                Token::LabelUsage { name } => {
-                    println!("LabelUsage: {}", name);
+    /// Like `assemble`, for source loaded after `code_base` bytes of code and
+    /// `data_base` bytes of read-only data, which define the labels in
+    /// `symbols`. The program holds only the new code and data but all the
+    /// symbols, the REPL uses it to assemble one line at a time.
+    pub fn assemble_at(
+        &mut self,
+        raw: &str,
+        code_base: u32,
+        data_base: u32,
+        symbols: SymbolTable,
+    ) -> Result<Program, Vec<AsmError>> {
+        let (instructions, mut errors) = self.parse_lines(raw);
+        self.symbols = symbols;
+        self.code_base = code_base;
+        self.data_base = data_base;
+        if let Err(first_phase) = self.process_first_phase(&instructions) {
+            errors.extend(first_phase);
+        }
+        if errors.is_empty() {
+            match self.process_second_phase(&instructions) {
+                Ok(program) => self.program = program,
+                Err(second_phase) => errors = second_phase,
+            }
+        }
+        if !errors.is_empty() {
+            errors.sort_by_key(|error| (error.line, error.column));
+            for error in &mut errors {
+                error.file = self.file.clone();
+                error.source_line = raw
+                    .lines()
+                    .nth(error.line.saturating_sub(1))
+                    .unwrap_or_default()
+                    .to_string();
+            }
+            return Err(errors);
+        }
+        let entry = match self.symbols.symbol("main") {
+            Some(symbol) if symbol.symbol_type == SymbolType::Label => {
+                symbol.offset.unwrap_or_default()
+            }
+            _ => 0,
+        };
+        Ok(Program {
+            entry,
+            code: self.program.clone(),
+            ro_data: self.ro.clone(),
+            symbols: self.symbols.clone(),
+        })
+    }
+    /// First pass: walks the instructions adding up their sizes and records
+    /// the offset of every label declaration in the symbol table. Data
+    /// directives are laid out in the read-only section right away, labels
+    /// in the data section point into it.
+    pub fn process_first_phase(
+        &mut self,
+        instructions: &[AsmInstruction],
+    ) -> Result<(), Vec<AsmError>> {
+        self.ro.clear();
+        let mut errors = vec![];
+        let mut section = Section::Code;
+        let mut offset = self.code_base;
+        for instruction in instructions {
+            if let Err(error) = self.lay_out(instruction, &mut section, offset) {
+                errors.push(error);
+            }
+            offset += instruction.size() as u32;
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+    fn lay_out(
+        &mut self,
+        instruction: &AsmInstruction,
+        section: &mut Section,
+        offset: u32,
+    ) -> Result<(), AsmError> {
+        let mut symbol_type = SymbolType::Label;
+        let mut data = None;
+        if let Some(Token::Directive { name }) = &instruction.directive {
+            match name.as_str() {
+                "code" => *section = Section::Code,
+                "data" => *section = Section::Data,
+                "asciiz" | "integer" if *section == Section::Data => {
+                    symbol_type = match name.as_str() {
+                        "asciiz" => SymbolType::IrString,
+                        _ => SymbolType::Integer,
+                    };
+                    data = Some(instruction.data_bytes()?);
                 }
-                Token::Directive { name } => {
-                    println!("Directive: {}", name);
+                "asciiz" | "integer" => {
+                    return Err(instruction.error(
+                        AsmErrorKind::WrongSection {
+                            found: format!("directive `.{}`", name),
+                            section: *section,
+                        },
+                        instruction.head_span,
+                    ))
                 }
-                Token::IrString { name } => {
-                    println!("IrString: {}", name);
+                _ => {
+                    return Err(instruction.error(
+                        AsmErrorKind::UnknownDirective { name: name.clone() },
+                        instruction.head_span,
+                    ))
                 }
-                Token::Comment => {}
-                _ => {}
             }
-            index += 1;
         }
+        if let (Some(Token::Op { code }), Section::Data) = (&instruction.opcode, *section) {
+            return Err(instruction.error(
+                AsmErrorKind::WrongSection {
+                    found: format!("opcode `{}`", code.mnemonic()),
+                    section: *section,
+                },
+                instruction.head_span,
+            ));
+        }
+        if let Some(Token::LabelDeclaration { name }) = &instruction.label {
+            if self.symbols.has_symbol(name) {
+                return Err(instruction.error(
+                    AsmErrorKind::DuplicateLabel { name: name.clone() },
+                    instruction.label_span,
+                ));
+            }
+            if *section == Section::Data && data.is_none() {
+                return Err(instruction.error(
+                    AsmErrorKind::BareDataLabel { name: name.clone() },
+                    instruction.label_span,
+                ));
+            }
+            let value = match section {
+                Section::Code => offset,
+                Section::Data => self.data_base + self.ro.len() as u32,
+            };
+            self.symbols
+                .add_symbol(Symbol::new_with_offset(name.clone(), symbol_type, value));
+        }
+        if let Some(data) = data {
+            self.ro.extend(data);
+        }
+        Ok(())
     }
-    pub fn compile(&self, tokens: Vec<Token>) -> Result<Vec<u8>, &str> {
-        let mut index = 0;
+    /// Second pass: encodes the instructions, replacing label usages with the
+    /// offsets found in the first pass.
+    pub fn process_second_phase(
+        &self,
+        instructions: &[AsmInstruction],
+    ) -> Result<Vec<u8>, Vec<AsmError>> {
         let mut result: Vec<u8> = Vec::new();
-
-        while index < tokens.len() {
-            let token = &tokens[index];
-            match token {
-                Token::Op { code } => {
-                    match &code {
-                        Opcode::LOAD => {
-                            result.push(1);
-
-                            if index + 1 < tokens.len() {
-                                if let Token::Register { reg_num } = tokens[index + 1] {
-                                    result.push(reg_num as u8); // Push register number directly
-                                }
-                            }
-                            if index + 2 < tokens.len() {
-                                if let Token::IntegerOperand { value } = tokens[index + 2] {
-                                    let results = helpers::parse_i32_to_vecu8(value);
-                                    result.extend(results);
-                                }
-                            }
-                            index += 2;
-                        }
-                        Opcode::HLT => result.push(0),
-                        Opcode::IGL => result.push(0),
-                        Opcode::ADD => result.push(2),
-                        Opcode::SUB => result.push(3),
-                        Opcode::MUL => result.push(4),
-                        Opcode::DIV => result.push(5),
-                        Opcode::JMP => result.push(6),
-                        Opcode::JMPF => result.push(7),
-                        Opcode::EQ => result.push(8),
-                        Opcode::NEQ => result.push(9),
-                        Opcode::GT => result.push(10),
-                        Opcode::LT => result.push(11),
-                        Opcode::GTQ => result.push(12),
-                        Opcode::LTQ => result.push(13),
-                        Opcode::JMPEQ => result.push(14),
-                        Opcode::LABEL => result.push(15),
-                        Opcode::SQUARE => result.push(16),
-                        Opcode::ALLOC => result.push(17),
-                        Opcode::SET => result.push(18),
-                    }
-                }
-                Token::Register { reg_num } => {
-                    result.push(*reg_num as u8);
-                    if index + 1 < tokens.len() {
-                        if let Token::Register { reg_num } = tokens[index + 1] {
-                        } else {
-                            result.push(0)
-                        }
-                    }
-                }
-                Token::IntegerOperand { value } => {
-                    let results = helpers::parse_i32_to_vecu8(*value);
-                    result.extend(results);
-                }
-                Token::FloatOperand { value } => {
-                    println!("FloatOperand: {}", value);
-                    result.push(*value as u8); // Placeholder: convert float to integer
-                }
-                Token::LabelDeclaration { name } => {
-                    println!("LabelDeclaration: {}", name);
-                    result.push(2);
-                }
-                Token::LabelUsage { name } => {
-                    println!("LabelUsage: {}", name);
-                    result.push(3); // Placeholder value for label usage
-                }
-                Token::Directive { name } => {
-                    println!("Directive: {}", name);
-                    if index + 1 < tokens.len() {
-                        if let Token::IntegerOperand { value } = tokens[index + 1] {
-                            let results = helpers::parse_i32_to_vecu8(value);
-                            let result_len = results.len();
-                            result.push(1);
-                            result.push(31);
-                            result.extend(results);
-                            index += 1 + result_len;
-                        }
-                    }
-                }
-                Token::IrString { name } => {
-                    println!("IrString: {}", name);
-                    result.push(17);
-                    result.push(31);
-                    result.extend(name.as_bytes());
-                    println!("{:?}", result)
-                }
-                Token::Comment => {}
+        let mut errors = vec![];
+        for instruction in instructions {
+            match instruction.to_bytes(&self.symbols, &self.natives) {
+                Ok(bytes) => result.extend(bytes),
+                Err(error) => errors.push(error),
             }
-            index += 1;
         }
-        if !result.is_empty() {
-            Ok(result)
-        } else {
-            Err("No tokens to compile")
+        match errors.is_empty() {
+            true => Ok(result),
+            false => Err(errors),
         }
     }
-    fn to_asm_instructions(&self, tokens: Vec<Token>) -> Result<Vec<AsmInstruction>, &str> {
-        let mut index = 0;
+    /// Parses the source line by line, every line holding an instruction,
+    /// a directive or a label becomes one `AsmInstruction`.
+    pub fn to_asm_instructions(&self, raw: &str) -> Result<Vec<AsmInstruction>, Vec<AsmError>> {
+        match self.parse_lines(raw) {
+            (instructions, errors) if errors.is_empty() => Ok(instructions),
+            (_, errors) => Err(errors),
+        }
+    }
+    fn parse_lines(&self, raw: &str) -> (Vec<AsmInstruction>, Vec<AsmError>) {
         let mut result: Vec<AsmInstruction> = Vec::new();
-
-        while index < tokens.len() {
-            let token = &tokens[index];
-            match token {
-                Token::Op { code } => match &code {
-                    Opcode::LOAD => {
-                        let mut new_instruction = AsmInstruction {
-                            directive: None,
-                            label: None,
-                            opcode: Some(Token::Op { code: Opcode::LOAD }),
-                            operand1: None,
-                            operand2: None,
-                            operand3: None,
-                        };
-                        if index + 2 < tokens.len() {
-                            if let Token::Register { reg_num } = tokens[index + 1] {
-                                new_instruction.operand1 =
-                                    Some(Token::Register { reg_num: reg_num })
-                            }
-                            if let Token::IntegerOperand { value } = tokens[index + 2] {
-                                new_instruction.operand2 =
-                                    Some(Token::IntegerOperand { value: value })
-                            }
-                            if let Token::FloatOperand { value } = tokens[index + 2] {
-                                new_instruction.operand2 =
-                                    Some(Token::FloatOperand { value: value })
-                            }
-                        }
-                        result.push(new_instruction);
-                        index += 2;
-                    }
-                    _ => {
-                        let mut new_instruction = AsmInstruction {
-                            directive: None,
-                            label: None,
-                            opcode: Some(Token::Op { code: code.clone() }),
-                            operand1: None,
-                            operand2: None,
-                            operand3: None,
-                        };
-                        if index + 3 < tokens.len() {
-                            if let Token::Register { reg_num } = tokens[index + 1] {
-                                new_instruction.operand1 =
-                                    Some(Token::Register { reg_num: reg_num })
-                            }
-                            if let Token::Register { reg_num } = tokens[index + 2] {
-                                new_instruction.operand2 =
-                                    Some(Token::Register { reg_num: reg_num })
-                            }
-                            if let Token::Register { reg_num } = tokens[index + 3] {
-                                new_instruction.operand3 =
-                                    Some(Token::Register { reg_num: reg_num })
-                            }
-                        }
-                        result.push(new_instruction);
-                        index += 3;
-                    }
-                },
-                Token::LabelDeclaration { name } => {
-                    println!("LabelDeclaration: {}", name);
-                }
-                Token::LabelUsage { name } => {
-                    println!("LabelUsage: {}", name);
+        let mut errors = vec![];
+        for (index, line) in raw.lines().enumerate() {
+            let mut instruction = match parsers::parse_instruction(line) {
+                Ok((_, instruction)) => instruction,
+                Err(nom::Err::Error(error)) | Err(nom::Err::Failure(error)) => {
+                    let parsed = line[..line.len() - error.input.len()].trim();
+                    let head_expected = parsed.is_empty() || parsed.ends_with(':');
+                    let head = parsed
+                        .split_whitespace()
+                        .find(|word| !word.ends_with(':'))
+                        .and_then(operand_count);
+                    errors.push(diagnose(index + 1, line, error.input, head_expected, head));
+                    continue;
                 }
-                Token::Directive { name } => {
-                    println!("Directive: {}", name);
-                }
-                Token::IrString { name } => {
-                    println!("IrString: {}", name);
-
-                    println!("{:?}", result)
-                }
-                Token::Comment => {}
-                _ => {}
+                Err(nom::Err::Incomplete(_)) => unreachable!("complete parsers never ask for more"),
+            };
+            if instruction.opcode.is_none()
+                && instruction.directive.is_none()
+                && instruction.label.is_none()
+            {
+                continue;
             }
-            index += 1;
-        }
-        if !result.is_empty() {
-            Ok(result)
-        } else {
-            Err("No tokens to compile")
+            instruction.line = index + 1;
+            result.push(instruction);
         }
+        (result, errors)
     }
 
-    pub fn tokenize<'a>(&self, input: &'a str) -> IResult<&'a str, Vec<Token>> {
-        let (input, _) = multispace0(input)?;
+    /// Splits the source into tokens, comments included. Every token has to
+    /// end at whitespace, a comment or the end of the line.
+    pub fn tokenize(&self, input: &str) -> Result<Vec<Token>, AsmError> {
         let mut tokens = Vec::new();
-        let mut remaining = input;
-
-        while !remaining.is_empty() {
-            let (new_remaining, token) = alt((
-                parsers::parse_opcode,
-                parsers::parse_register,
-                parsers::parse_integer,
-                parsers::parse_float,
-                parsers::parse_label_declaration,
-                parsers::parse_label_usage,
-                parsers::parse_directive,
-                parsers::parse_string,
-                parsers::parse_comment,
-            ))(remaining)?;
-            tokens.push(token);
-            let (new_remaining, _) = multispace0(new_remaining)?;
-            remaining = new_remaining;
+        for (index, line) in input.lines().enumerate() {
+            let mut remaining = line.trim_start();
+            while !remaining.is_empty() {
+                let (new_remaining, token) = parsers::parse_token(remaining)
+                    .map_err(|_| diagnose(index + 1, line, remaining, true, None))?;
+                tokens.push(token);
+                remaining = new_remaining.trim_start();
+            }
         }
-        println!("{:?}", tokens);
-        Ok((remaining, tokens))
+        Ok(tokens)
+    }
+}
+
+fn is_integer_literal(word: &str) -> bool {
+    let digits = word.strip_prefix('-').unwrap_or(word);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// A float literal in the digits-and-exponent form `parse_float` only
+/// rejects because it overflows to infinity.
+fn is_float_literal(word: &str) -> bool {
+    word.starts_with(|c: char| c == '-' || c.is_ascii_digit())
+        && word.parse::<f64>().is_ok_and(f64::is_infinite)
+}
+
+/// The opcode or directive `word` names, with the number of operands it
+/// takes.
+fn operand_count(word: &str) -> Option<(&'static str, u8)> {
+    match word {
+        ".asciiz" => Some((".asciiz", 1)),
+        ".integer" => Some((".integer", 1)),
+        ".data" => Some((".data", 0)),
+        ".code" => Some((".code", 0)),
+        _ => Opcode::from_mnemonic(word)
+            .map(|code| (code.mnemonic(), code.info().operands.len() as u8)),
     }
 }
 
+/// Works out what is wrong with `rest`, the part of `line` no parser
+/// accepted, and points at the offending token. `head` is the opcode or
+/// directive already parsed on the line, if any.
+fn diagnose(
+    line_number: usize,
+    line: &str,
+    rest: &str,
+    head_expected: bool,
+    head: Option<(&'static str, u8)>,
+) -> AsmError {
+    let end = rest
+        .find(|c: char| c.is_whitespace() || c == ';')
+        .unwrap_or(rest.len())
+        .max(rest.chars().next().map_or(0, char::len_utf8));
+    let word = &rest[..end];
+    let mut span = Span::of(line, word);
+    let kind = match (parsers::parse_operand(word), head) {
+        (Ok(("", operand)), Some((head, expected))) => AsmErrorKind::ExtraOperand {
+            head,
+            expected,
+            found: operand.describe().into(),
+        },
+        (Ok(("", operand)), None) => AsmErrorKind::UnexpectedOperand {
+            found: operand.describe(),
+        },
+        _ if word.starts_with('"') => {
+            span = Span::of(line, rest.trim_end());
+            AsmErrorKind::UnterminatedString
+        }
+        _ if is_integer_literal(word) => AsmErrorKind::IntegerOutOfRange {
+            found: word.to_string(),
+        },
+        _ if is_float_literal(word) => AsmErrorKind::FloatOutOfRange {
+            found: word.to_string(),
+        },
+        _ if word.starts_with('$') => AsmErrorKind::BadRegister {
+            found: word.to_string(),
+        },
+        _ if head_expected && word.starts_with(|c: char| c.is_alphabetic()) => {
+            AsmErrorKind::UnknownMnemonic {
+                name: word.to_string(),
+            }
+        }
+        _ => AsmErrorKind::UnknownToken {
+            found: word.to_string(),
+        },
+    };
+    AsmError::new(kind, line_number, span)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::instruction::OperandKind;
+    use crate::vm::{ExitStatus, VM};
 
     #[test]
     fn test_tokens_to_instructions() {
         let input = "ADD $1 $2 $3\n LOAD $6 1024\n";
         let assembler = Assembler::new();
-        let (remaining, tokens) = assembler.tokenize(input).unwrap();
-        assert_eq!(remaining, "");
-        let results = assembler.to_asm_instructions(tokens).unwrap();
-        for i in results.iter() {
-            println!("{:?}", i)
+        let results = assembler.to_asm_instructions(input).unwrap();
+        assert_eq!(
+            results,
+            vec![
+                AsmInstruction {
+                    opcode: Some(Token::Op { code: Opcode::ADD }),
+                    label: None,
+                    directive: None,
+                    operand1: Some(Token::Register { reg_num: 1 }),
+                    operand2: Some(Token::Register { reg_num: 2 }),
+                    operand3: Some(Token::Register { reg_num: 3 }),
+                    line: 1,
+                    label_span: Span { column: 0, len: 0 },
+                    head_span: Span { column: 0, len: 3 },
+                    operand_spans: [
+                        Span { column: 4, len: 2 },
+                        Span { column: 7, len: 2 },
+                        Span { column: 10, len: 2 },
+                    ],
+                },
+                AsmInstruction {
+                    opcode: Some(Token::Op { code: Opcode::LOAD }),
+                    label: None,
+                    directive: None,
+                    operand1: Some(Token::Register { reg_num: 6 }),
+                    operand2: Some(Token::IntegerOperand { value: 1024 }),
+                    operand3: None,
+                    line: 2,
+                    label_span: Span { column: 1, len: 0 },
+                    head_span: Span { column: 1, len: 4 },
+                    operand_spans: [
+                        Span { column: 6, len: 2 },
+                        Span { column: 9, len: 4 },
+                        Span::default(),
+                    ],
+                },
+            ]
+        );
+    }
+    #[test]
+    fn test_labels_and_directives_are_attached() {
+        let input = "; header\n\nstart: .asciiz \"hi there\" ; greeting\nloop:\n  jmp @loop";
+        let assembler = Assembler::new();
+        let results = assembler.to_asm_instructions(input).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0],
+            AsmInstruction {
+                opcode: None,
+                label: Some(Token::LabelDeclaration {
+                    name: "start".to_string()
+                }),
+                directive: Some(Token::Directive {
+                    name: "asciiz".to_string()
+                }),
+                operand1: Some(Token::IrString {
+                    name: "hi there".to_string()
+                }),
+                operand2: None,
+                operand3: None,
+                line: 3,
+                label_span: Span { column: 0, len: 6 },
+                head_span: Span { column: 7, len: 7 },
+                operand_spans: [
+                    Span {
+                        column: 15,
+                        len: 10
+                    },
+                    Span::default(),
+                    Span::default(),
+                ],
+            }
+        );
+        assert_eq!(results[1].line, 4);
+        assert!(results[1].opcode.is_none());
+        assert_eq!(results[2].line, 5);
+        assert_eq!(
+            results[2].operand1,
+            Some(Token::LabelUsage {
+                name: "loop".to_string()
+            })
+        );
+    }
+    fn first_error(input: &str) -> (usize, usize, usize, AsmErrorKind) {
+        let error = Assembler::new()
+            .to_asm_instructions(input)
+            .unwrap_err()
+            .remove(0);
+        (error.line, error.column, error.len, error.kind)
+    }
+    #[test]
+    fn test_line_parser_errors_carry_position() {
+        assert_eq!(
+            first_error("halt\nadd $1 $2 $3 $4"),
+            (
+                2,
+                14,
+                2,
+                AsmErrorKind::ExtraOperand {
+                    head: "add",
+                    expected: 3,
+                    found: "register `$4`".into()
+                }
+            )
+        );
+        assert_eq!(
+            first_error("  addx $1"),
+            (
+                1,
+                3,
+                4,
+                AsmErrorKind::UnknownMnemonic {
+                    name: "addx".to_string()
+                }
+            )
+        );
+        assert_eq!(
+            first_error("loop: frob"),
+            (
+                1,
+                7,
+                4,
+                AsmErrorKind::UnknownMnemonic {
+                    name: "frob".to_string()
+                }
+            )
+        );
+        assert_eq!(
+            first_error("load $x 1"),
+            (
+                1,
+                6,
+                2,
+                AsmErrorKind::BadRegister {
+                    found: "$x".to_string()
+                }
+            )
+        );
+        assert_eq!(
+            first_error(".data\nmsg: .asciiz \"no end  "),
+            (2, 14, 7, AsmErrorKind::UnterminatedString)
+        );
+        assert_eq!(
+            first_error("load $1 %bogus"),
+            (
+                1,
+                9,
+                6,
+                AsmErrorKind::UnknownToken {
+                    found: "%bogus".to_string()
+                }
+            )
+        );
+        let instructions = Assembler::new()
+            .to_asm_instructions("load $1 1.5 ; float")
+            .unwrap();
+        assert_eq!(
+            instructions[0].operand2,
+            Some(Token::FloatOperand { value: 1.5 })
+        );
+    }
+
+    #[test]
+    fn test_register_validation_and_aliases() {
+        assert_eq!(
+            compile("load $31 7\nadd $zero $sp $fp\nsub $ra $31 $0"),
+            Ok(vec![19, 31, 0, 7, 2, 0, 29, 30, 3, 31, 31, 0])
+        );
+        assert_eq!(
+            compile("add $ZERO $SP $Fp\nsub $Ra $31 $0"),
+            Ok(vec![2, 0, 29, 30, 3, 31, 31, 0])
+        );
+        for bad in ["$32", "$40", "$999", "$99999999999999999999", "$pc", "$PC"] {
+            assert_eq!(
+                first_error(&format!("load {} 1", bad)),
+                (
+                    1,
+                    6,
+                    bad.len(),
+                    AsmErrorKind::BadRegister {
+                        found: bad.to_string()
+                    }
+                )
+            );
+        }
+        let error = Assembler::new().assemble("add $1 $2 $32").unwrap_err();
+        assert_eq!(
+            error[0].kind.to_string(),
+            "invalid register `$32`, expected `$0` to `$31`, `$f0` to `$f31`, `$l0` to `$l31` or one of `$zero`, `$sp`, `$fp`, `$ra`"
+        );
+    }
+    #[test]
+    fn test_tokenize_opcodes_from_table() {
+        let assembler = Assembler::new();
+        let tokens = assembler.tokenize("set $1\nSQUARE $1 $2").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Op { code: Opcode::SET },
+                Token::Register { reg_num: 1 },
+                Token::Op {
+                    code: Opcode::SQUARE
+                },
+                Token::Register { reg_num: 1 },
+                Token::Register { reg_num: 2 },
+            ]
+        );
+    }
+
+    fn compile(input: &str) -> Result<Vec<u8>, AsmErrorKind> {
+        Assembler::new()
+            .assemble(input)
+            .map(|program| program.code)
+            .map_err(|mut diagnostics| diagnostics.remove(0).kind)
+    }
+    #[test]
+    fn test_compile_follows_operand_schema() {
+        assert_eq!(compile("add $1 $2 $3"), Ok(vec![2, 1, 2, 3]));
+        assert_eq!(compile("eq $1 $2"), Ok(vec![8, 1, 2]));
+        assert_eq!(compile("load $1 70000"), Ok(vec![1, 1, 0, 1, 17, 112]));
+        assert_eq!(compile("jmp $4\nhalt"), Ok(vec![6, 4, 0]));
+    }
+    #[test]
+    fn test_compile_rejects_operand_mismatch() {
+        assert_eq!(
+            compile("load $1 $2"),
+            Err(AsmErrorKind::OperandMismatch {
+                opcode: Opcode::LOAD,
+                position: 2,
+                expected: OperandKind::Imm32,
+                found: "register `$2`".to_string(),
+            })
+        );
+        assert_eq!(
+            compile("add $1 $2\nhalt"),
+            Err(AsmErrorKind::MissingOperand {
+                opcode: Opcode::ADD,
+                position: 3,
+                expected: OperandKind::Register,
+            })
+        );
+        assert_eq!(
+            compile("jmp $1 $2"),
+            Err(AsmErrorKind::ExtraOperand {
+                head: "jmp",
+                expected: 1,
+                found: "register `$2`".into(),
+            })
+        );
+        assert_eq!(
+            compile("jmp @end 7\nend:"),
+            Err(AsmErrorKind::ExtraOperand {
+                head: "jmp",
+                expected: 1,
+                found: "integer `7`".into(),
+            })
+        );
+        assert_eq!(
+            compile("load $1 2").map(|bytes| bytes.len()),
+            Ok(Opcode::LOADS.info().size())
+        );
+        assert_eq!(
+            compile("loads $1 40000"),
+            Err(AsmErrorKind::ImmediateOutOfRange {
+                opcode: Opcode::LOADS,
+                position: 2,
+                expected: OperandKind::Imm16,
+                value: 40000,
+            })
+        );
+    }
+    #[test]
+    fn test_compile_picks_short_load() {
+        assert_eq!(compile("load $1 500"), Ok(vec![19, 1, 1, 244]));
+        assert_eq!(compile("load $1 -1"), Ok(vec![19, 1, 255, 255]));
+        assert_eq!(compile("load $1 -32768"), Ok(vec![19, 1, 128, 0]));
+        assert_eq!(compile("load $1 32768"), Ok(vec![1, 1, 0, 0, 128, 0]));
+        assert_eq!(
+            compile("load $1 -70000"),
+            Ok(vec![1, 1, 255, 254, 238, 144])
+        );
+    }
+    #[test]
+    fn test_labels_resolve_to_offsets() {
+        let mut assembler = Assembler::new();
+        let source = "load $1 0\nload $2 1\nload $3 5\nloop: add $1 $2 $1\neq $1 $3\njmpeq @done\njmp @loop\ndone:\nhalt";
+        let program = assembler.assemble(source).unwrap().code;
+        assert_eq!(assembler.symbols.symbol_value("loop"), Some(12));
+        assert_eq!(assembler.symbols.symbol_value("done"), Some(29));
+        assert_eq!(&program[19..29], &[21, 0, 0, 0, 29, 20, 0, 0, 0, 12]);
+
+        let mut test_vm = VM::new();
+        test_vm.program = program;
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[1], 5);
+    }
+    #[test]
+    fn test_branch_forms() {
+        let source =
+            "load $1 0\nload $2 1\nload $3 5\nloop: add $1 $2 $1\neq $1 $3\njmpneq @loop\nhalt";
+        let program = Assembler::new().assemble(source).unwrap().code;
+        assert_eq!(&program[19..25], &[64, 0, 0, 0, 12, 0]);
+        let mut test_vm = VM::new();
+        test_vm.program = program;
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[1], 5);
+
+        assert_eq!(compile("jmpneq $4\njmpb $5"), Ok(vec![63, 4, 65, 5]));
+        assert_eq!(compile("jmpneq 9"), Ok(vec![64, 0, 0, 0, 9]));
+        assert_eq!(
+            compile("label $1"),
+            Err(AsmErrorKind::UnknownMnemonic {
+                name: "label".to_string()
+            })
+        );
+    }
+    #[test]
+    fn test_load_label_address() {
+        assert_eq!(
+            compile("load $1 @end\nhalt\nend:"),
+            Ok(vec![1, 1, 0, 0, 0, 7, 0])
+        );
+        let source = "load $1 @skip\njmp $1\nload $2 1\nskip: load $3 2\nhalt";
+        let mut test_vm = VM::new();
+        test_vm.program = Assembler::new().assemble(source).unwrap().code;
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[2], 0);
+        assert_eq!(test_vm.registers[3], 2);
+    }
+    #[test]
+    fn test_label_errors() {
+        assert_eq!(
+            compile("jmp @nowhere"),
+            Err(AsmErrorKind::UndefinedLabel {
+                name: "nowhere".to_string()
+            })
+        );
+        assert_eq!(
+            compile("a: halt\na: halt"),
+            Err(AsmErrorKind::DuplicateLabel {
+                name: "a".to_string()
+            })
+        );
+        let kind = compile(".data\nmsg: .asciiz \"hi\"\n.code\njmp @msg");
+        assert_eq!(
+            kind,
+            Err(AsmErrorKind::WrongLabelType {
+                name: "msg".to_string(),
+                expected: SymbolType::Label,
+                found: SymbolType::IrString,
+            })
+        );
+        assert_eq!(
+            kind.unwrap_err().to_string(),
+            "`msg` is a data label, not a code label"
+        );
+        let bare = [
+            (".data\nmsg:\n.asciiz \"hi\"\n.code\njmp @msg", "msg"),
+            ("jmp @x\n.data\nx:", "x"),
+        ];
+        for (source, name) in bare {
+            assert_eq!(
+                compile(source),
+                Err(AsmErrorKind::BareDataLabel {
+                    name: name.to_string()
+                })
+            );
         }
+        assert_eq!(
+            compile(".data\nkey: .integer 7\n.code\ncall @key"),
+            Err(AsmErrorKind::WrongLabelType {
+                name: "key".to_string(),
+                expected: SymbolType::Label,
+                found: SymbolType::Integer,
+            })
+        );
+    }
+    #[test]
+    fn test_assemble_multi_line_source() {
+        let mut assembler = Assembler::new();
+        let source = "
+            load $1 10
+            load $2 32
+            ; add them up
+            add $1 $2 $3
+            jmp @end
+            halt
+            end: halt
+        ";
+        let program = assembler.assemble(source).unwrap();
+        assert_eq!(assembler.program, program.code);
+        assert_eq!(program.symbols.symbol_value("end"), Some(18));
+
+        let mut test_vm = VM::new();
+        test_vm.program = program.code;
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[3], 42);
+        assert_eq!(test_vm.pc, 19);
+    }
+    #[test]
+    fn test_assemble_collects_diagnostics() {
+        let mut assembler = Assembler::new();
+        let errors = assembler
+            .assemble("load $1 1\n%bogus $2\n.bss\nfrob $1\na: halt\na: halt")
+            .unwrap_err();
+        let found: Vec<(usize, AsmErrorKind)> = errors
+            .into_iter()
+            .map(|error| (error.line, error.kind))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    2,
+                    AsmErrorKind::UnknownToken {
+                        found: "%bogus".to_string()
+                    }
+                ),
+                (
+                    3,
+                    AsmErrorKind::UnknownDirective {
+                        name: "bss".to_string()
+                    }
+                ),
+                (
+                    4,
+                    AsmErrorKind::UnknownMnemonic {
+                        name: "frob".to_string()
+                    }
+                ),
+                (
+                    6,
+                    AsmErrorKind::DuplicateLabel {
+                        name: "a".to_string()
+                    }
+                ),
+            ]
+        );
+        let lines: Vec<usize> = assembler
+            .assemble("jmp @missing\nload $1 @gone\nadd $1 $2")
+            .unwrap_err()
+            .iter()
+            .map(|error| error.line)
+            .collect();
+        assert_eq!(lines, vec![1, 2, 3]);
+    }
+    #[test]
+    fn test_diagnostics_render_with_caret() {
+        let mut assembler = Assembler::new();
+        assembler.file = Some("prog.asm".to_string());
+        let errors = assembler.assemble("halt\n  jmp @nowhere").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "error: label `nowhere` is not defined\n --> prog.asm:2:7\n  |\n2 |   jmp @nowhere\n  |       ^^^^^^^^"
+        );
+        let errors = Assembler::new().assemble("add $1 $2").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "error: `add` expects 3 operands, operand 3 (register) is missing\n --> <input>:1:10\n  |\n1 | add $1 $2\n  |          ^"
+        );
+        let messages: Vec<String> = [
+            "add $1 $2 $3 $4",
+            "jmp $1 $2",
+            "halt $1",
+            ".data\nx: .integer 1 2",
+        ]
+        .iter()
+        .map(|source| {
+            Assembler::new().assemble(source).unwrap_err()[0]
+                .kind
+                .to_string()
+        })
+        .collect();
+        assert_eq!(
+            messages,
+            [
+                "`add` takes 3 operands, found an extra register `$4`",
+                "`jmp` takes 1 operand, found an extra register `$2`",
+                "`halt` takes no operands, found an extra register `$1`",
+                "`.integer` takes 1 operand, found an extra integer `2`",
+            ]
+        );
+    }
+    #[test]
+    fn test_recursive_subroutine() {
+        // Sums 1..=n recursively, saving n on the stack across the call.
+        let source = "
+            main: load $1 10
+                  load $2 0
+                  load $3 1
+                  call @sum
+                  halt
+            sum:  eq $1 $2
+                  jmpeq @done
+                  push $1
+                  sub $1 $3 $1
+                  call @sum
+                  pop $1
+                  add $1 $4 $4
+                  ret
+            done: load $4 0
+                  ret
+        ";
+        let program = Assembler::new().assemble(source).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_program(program);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[4], 55);
+        assert_eq!(test_vm.registers[29], 0);
+    }
+    #[test]
+    fn test_copy_string_to_heap() {
+        let source = "
+            .data
+            msg: .asciiz \"pecet\"
+            .code
+                  load $1 @msg
+                  load $3 6
+                  load $4 1
+                  load $5 0
+                  alloc $3 $2
+            copy: loadb $6 $1 0
+                  storeb $6 $2 0
+                  add $1 $4 $1
+                  add $2 $4 $2
+                  neq $6 $5
+                  jmpeq @copy
+                  halt
+        ";
+        let program = Assembler::new().assemble(source).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_program(program);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(&test_vm.heap[..6], b"pecet\0");
+    }
+    #[test]
+    fn test_rotate_xor_hash() {
+        // h = rotl(h, 5) ^ byte over a NUL terminated string, then packs
+        // the low byte of the hash and the string length into one word.
+        let source = "
+            .data
+            key: .asciiz \"pecetVM\"
+            .code
+                  load $1 @key
+                  load $2 0       ; hash
+                  load $3 5
+                  load $4 27
+                  load $7 0       ; length
+                  load $8 255
+                  load $9 8
+            next: loadb $5 $1 0
+                  eq $5 $zero
+                  jmpeq @done
+                  shl $2 $3 $6
+                  shr $2 $4 $2
+                  or $2 $6 $2
+                  xor $2 $5 $2
+                  inc $1
+                  inc $7
+                  jmp @next
+            done: and $2 $8 $10
+                  shl $10 $9 $10
+                  or $10 $7 $10
+                  halt
+        ";
+        let expected = b"pecetVM"
+            .iter()
+            .fold(0u32, |hash, byte| hash.rotate_left(5) ^ *byte as u32);
+        let program = Assembler::new().assemble(source).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_program(program);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[2] as u32, expected);
+        assert_eq!(test_vm.registers[10], ((expected & 255) << 8 | 7) as i32);
+    }
+    #[test]
+    fn test_long_factorial() {
+        let source = "
+                  loadl $l1 1     ; result
+                  loadl $l2 20
+                  loadl $l3 1
+                  loadl $l4 0
+            next: mull $l1 $l2 $l1
+                  subl $l2 $l3 $l2
+                  eql $l2 $l4
+                  jmpeq @done
+                  jmp @next
+            done: loadl $l5 -9223372036854775808
+                  halt
+        ";
+        let program = Assembler::new().assemble(source).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_program(program);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.long_registers[1], (1..=20).product::<i64>());
+        assert_eq!(test_vm.long_registers[5], i64::MIN);
+
+        assert_eq!(
+            compile("load $1 2147483648"),
+            Err(AsmErrorKind::ImmediateOutOfRange {
+                opcode: Opcode::LOAD,
+                position: 2,
+                expected: OperandKind::Imm32,
+                value: 2_147_483_648,
+            })
+        );
+        assert_eq!(
+            compile(".data\nbig: .integer 3000000000"),
+            Err(AsmErrorKind::DirectiveOperandMismatch {
+                directive: "integer".to_string(),
+                found: "integer `3000000000`".to_string(),
+            })
+        );
+    }
+    #[test]
+    fn test_native_references() {
+        let mut assembler = Assembler::new();
+        assembler.natives = vec!["print".to_string(), "sum".to_string()];
+        assert_eq!(
+            assembler
+                .assemble("calln !sum $2 3\ncalln 7 $0 0")
+                .unwrap()
+                .code,
+            vec![67, 0, 1, 2, 0, 3, 67, 0, 7, 0, 0, 0]
+        );
+        let error = assembler
+            .assemble("calln !missing $2 1")
+            .unwrap_err()
+            .remove(0);
+        assert_eq!(
+            (error.column, error.len, error.kind),
+            (
+                7,
+                8,
+                AsmErrorKind::UndefinedNative {
+                    name: "missing".to_string()
+                }
+            )
+        );
+        assert_eq!(
+            compile("calln @main $1 1\nmain:"),
+            Err(AsmErrorKind::OperandMismatch {
+                opcode: Opcode::CALLN,
+                position: 1,
+                expected: OperandKind::Native,
+                found: "label usage `main`".to_string(),
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm
+            .register_native("double", |_, args| Ok(args[0] * 2))
+            .unwrap();
+        let mut assembler = Assembler::new();
+        assembler.natives = test_vm.native_names();
+        let program = assembler
+            .assemble("load $4 21\ncalln !double $4 1\nhalt")
+            .unwrap();
+        test_vm.load_program(program);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[4], 42);
+    }
+    #[test]
+    fn test_newton_square_root() {
+        // x = (x + 2 / x) / 2, five times, then the root scaled to an integer.
+        let source = "
+                  loadf $f1 2.0
+                  loadf $f2 1
+                  loadf $f3 0.5
+                  loadf $f4 1e6
+                  load $1 5
+            step: divf $f1 $f2 $f5
+                  addf $f2 $f5 $f2
+                  mulf $f2 $f3 $f2
+                  dec $1
+                  jnz @step
+                  mulf $f2 $f4 $f6
+                  ftoi $f6 $2
+                  halt
+        ";
+        let program = Assembler::new().assemble(source).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_program(program);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert!((test_vm.float_registers[2] - std::f64::consts::SQRT_2).abs() < 1e-15);
+        assert_eq!(test_vm.registers[2], 1_414_213);
+        assert_eq!(
+            compile("addf $1 $f2 $f3"),
+            Err(AsmErrorKind::OperandMismatch {
+                opcode: Opcode::ADDF,
+                position: 1,
+                expected: OperandKind::FloatRegister,
+                found: "register `$1`".to_string(),
+            })
+        );
+    }
+    #[test]
+    fn test_data_section() {
+        let mut assembler = Assembler::new();
+        let source = "
+            .data
+            hello: .asciiz \"Hi!\"
+            answer: .integer 42
+            .integer -1
+            .code
+            start: load $1 @answer
+            halt
+        ";
+        let program = assembler.assemble(source).unwrap();
+        assert_eq!(
+            program.ro_data,
+            vec![72, 105, 33, 0, 0, 0, 0, 42, 255, 255, 255, 255]
+        );
+        assert_eq!(
+            program.symbols.symbols,
+            vec![
+                Symbol::new_with_offset("hello".to_string(), SymbolType::IrString, 0),
+                Symbol::new_with_offset("answer".to_string(), SymbolType::Integer, 4),
+                Symbol::new_with_offset("start".to_string(), SymbolType::Label, 0),
+            ]
+        );
+        assert_eq!(program.code, vec![1, 1, 0, 0, 0, 4, 0]);
+
+        let mut test_vm = VM::new();
+        test_vm.load_program(program.clone());
+        assert_eq!(test_vm.ro_data, program.ro_data);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[1], 4);
+    }
+    #[test]
+    fn test_section_errors() {
+        assert_eq!(
+            compile(".data\nadd $1 $2 $3"),
+            Err(AsmErrorKind::WrongSection {
+                found: "opcode `add`".to_string(),
+                section: Section::Data,
+            })
+        );
+        assert_eq!(
+            compile(".asciiz \"text\""),
+            Err(AsmErrorKind::WrongSection {
+                found: "directive `.asciiz`".to_string(),
+                section: Section::Code,
+            })
+        );
+        assert_eq!(
+            compile(".data\n.integer \"text\""),
+            Err(AsmErrorKind::DirectiveOperandMismatch {
+                directive: "integer".to_string(),
+                found: "string \"text\"".to_string(),
+            })
+        );
+        assert_eq!(
+            compile(".bss"),
+            Err(AsmErrorKind::UnknownDirective {
+                name: "bss".to_string()
+            })
+        );
     }
 }
@@ -1,96 +1,132 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, tag_no_case, take_while1},
-    character::complete::{alpha1, alphanumeric1, char, digit1, multispace0, space0, space1},
-    combinator::{map, opt, recognize},
-    number::complete::{float, recognize_float},
-    sequence::{pair, preceded, terminated},
+    bytes::complete::{tag, take_while, take_while_m_n},
+    character::complete::{char, digit1, one_of, space0, space1},
+    combinator::{eof, map_opt, map_res, opt, recognize},
+    error::{Error, ErrorKind},
+    sequence::{pair, preceded, terminated, tuple},
     IResult,
 };
 
-use crate::instruction::Opcode;
+use crate::instruction::{register_alias, Opcode, REGISTER_COUNT};
 
-use super::Token;
-pub fn parse_opcode(input: &str) -> IResult<&str, Token> {
-    let (input, opcode) = alt((
-        tag_no_case("halt"),
-        tag_no_case("load"),
-        tag_no_case("add"),
-        tag_no_case("sub"),
-        tag_no_case("mul"),
-        tag_no_case("div"),
-        tag_no_case("jmp"),
-        tag_no_case("jmpf"),
-        tag_no_case("eq"),
-        tag_no_case("neq"),
-        tag_no_case("gt"),
-        tag_no_case("lt"),
-        tag_no_case("gtq"),
-        tag_no_case("ltq"),
-        tag_no_case("jmpeq"),
-        tag_no_case("label"),
-        tag_no_case("square"),
-        tag_no_case("alloc"),
-    ))(input)?;
-
-    let code = match opcode.to_lowercase().as_str() {
-        "halt" => Opcode::HLT,
-        "load" => Opcode::LOAD,
-        "add" => Opcode::ADD,
-        "sub" => Opcode::SUB,
-        "mul" => Opcode::MUL,
-        "div" => Opcode::DIV,
-        "jmp" => Opcode::JMP,
-        "jmpf" => Opcode::JMPF,
-        "eq" => Opcode::EQ,
-        "neq" => Opcode::NEQ,
-        "gt" => Opcode::GT,
-        "lt" => Opcode::LT,
-        "gtq" => Opcode::GTQ,
-        "ltq" => Opcode::LTQ,
-        "jmpeq" => Opcode::JMPEQ,
-        "label" => Opcode::LABEL,
-        "square" => Opcode::SQUARE,
-        "alloc" => Opcode::ALLOC,
-        _ => Opcode::IGL,
-    };
+use super::{asm_instruction::AsmInstruction, error::Span, Token};
+
+// Granica tokenu: biały znak, początek komentarza albo koniec wejścia.
+// Dzięki niej `jmp2` nie jest czytane jako `jmp` i śmieci za nim.
+fn boundary(input: &str) -> IResult<&str, ()> {
+    match input.chars().next() {
+        None => Ok((input, ())),
+        Some(c) if c.is_whitespace() || c == ';' => Ok((input, ())),
+        Some(_) => Err(nom::Err::Error(Error::new(input, ErrorKind::Verify))),
+    }
+}
 
-    Ok((input, Token::Op { code }))
+// Parser dla identyfikatorów: litera albo `_`, potem litery, cyfry i `_`
+pub fn identifier(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        take_while_m_n(1, 1, |c: char| c.is_alphabetic() || c == '_'),
+        take_while(|c: char| c.is_alphanumeric() || c == '_'),
+    ))(input)
+}
+
+// Parser dla mnemoników, całe słowo musi być mnemonikiem z tabeli
+pub fn parse_opcode(input: &str) -> IResult<&str, Token> {
+    terminated(
+        map_opt(identifier, |mnemonic: &str| {
+            Opcode::from_mnemonic(mnemonic).map(|code| Token::Op { code })
+        }),
+        boundary,
+    )(input)
 }
 
+// Parser dla rejestrów: numer ($0 do $31) albo alias ($sp, $fp, $ra, $zero)
 pub fn parse_register(input: &str) -> IResult<&str, Token> {
     let (input, _) = char('$')(input)?;
-    let (input, reg_num) = map(digit1, |num: &str| num.parse::<u8>().unwrap())(input)?;
+    let (input, reg_num) = terminated(
+        alt((
+            map_opt(digit1, |num: &str| {
+                num.parse::<usize>()
+                    .ok()
+                    .filter(|index| *index < REGISTER_COUNT)
+                    .map(|index| index as u8)
+            }),
+            map_opt(identifier, register_alias),
+        )),
+        boundary,
+    )(input)?;
 
     Ok((input, Token::Register { reg_num }))
 }
 
-// Parser dla liczb całkowitych
-pub fn parse_integer(input: &str) -> IResult<&str, Token> {
-    let (input, value) = map(recognize(pair(opt(char('-')), digit1)), |num: &str| {
-        num.parse::<i32>().unwrap()
-    })(input)?;
+// Numer rejestru z osobnego banku, np. `$f3` dla prefiksu `$f`
+fn bank_register<'a>(prefix: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, u8> {
+    preceded(
+        tag(prefix),
+        terminated(
+            map_opt(digit1, |num: &str| {
+                num.parse::<usize>()
+                    .ok()
+                    .filter(|index| *index < REGISTER_COUNT)
+                    .map(|index| index as u8)
+            }),
+            boundary,
+        ),
+    )
+}
 
-    Ok((input, Token::IntegerOperand { value }))
+// Parser dla rejestrów zmiennoprzecinkowych ($f0 do $f31)
+pub fn parse_float_register(input: &str) -> IResult<&str, Token> {
+    let (input, reg_num) = bank_register("$f")(input)?;
+    Ok((input, Token::FloatRegister { reg_num }))
+}
+
+// Parser dla rejestrów 64-bitowych ($l0 do $l31)
+pub fn parse_long_register(input: &str) -> IResult<&str, Token> {
+    let (input, reg_num) = bank_register("$l")(input)?;
+    Ok((input, Token::LongRegister { reg_num }))
 }
-pub fn parse_data(input: &str) -> IResult<&str, Token> {
-    let (input, value) = map(
-        recognize(pair(opt(char('"')), opt(char('"')))),
-        |num: &str| num.parse::<i32>().unwrap(),
+
+// Parser dla liczb całkowitych
+pub fn parse_integer(input: &str) -> IResult<&str, Token> {
+    let (input, value) = terminated(
+        map_res(recognize(pair(opt(char('-')), digit1)), |num: &str| {
+            num.parse::<i64>()
+        }),
+        boundary,
     )(input)?;
 
     Ok((input, Token::IntegerOperand { value }))
 }
-
-// Parser dla liczb zmiennoprzecinkowych
+// Parser dla liczb zmiennoprzecinkowych (np. -1.5, 2.5e-3, 1e300), kropka
+// albo wykładnik odróżniają je od liczb całkowitych. Wartości nieskończone i
+// NaN zapisuje się jako `inf`, `-inf` i `NaN`, tak jak wypisuje je
+// disassembler, liczba poza zakresem f64 jest błędem
 pub fn parse_float(input: &str) -> IResult<&str, Token> {
-    let (input, value) = float(input)?;
+    let exponent = || recognize(tuple((one_of("eE"), opt(one_of("+-")), digit1)));
+    let (input, value) = terminated(
+        alt((
+            map_opt(
+                recognize(tuple((
+                    opt(char('-')),
+                    digit1,
+                    alt((
+                        recognize(pair(pair(char('.'), digit1), opt(exponent()))),
+                        exponent(),
+                    )),
+                ))),
+                |num: &str| num.parse::<f64>().ok().filter(|value| value.is_finite()),
+            ),
+            map_res(alt((tag("inf"), tag("-inf"), tag("NaN"))), str::parse),
+        )),
+        boundary,
+    )(input)?;
     Ok((input, Token::FloatOperand { value }))
 }
 
 // Parser dla deklaracji etykiet (np. label:)
 pub fn parse_label_declaration(input: &str) -> IResult<&str, Token> {
-    let (input, name) = take_while1(|c: char| c.is_alphanumeric() || c == ':')(input)?;
+    let (input, name) = terminated(terminated(identifier, char(':')), boundary)(input)?;
     Ok((
         input,
         Token::LabelDeclaration {
@@ -99,9 +135,9 @@ pub fn parse_label_declaration(input: &str) -> IResult<&str, Token> {
     ))
 }
 
-// Parser dla użycia etykiet
+// Parser dla użycia etykiet (np. @label)
 pub fn parse_label_usage(input: &str) -> IResult<&str, Token> {
-    let (input, name) = take_while1(|c: char| c.is_alphanumeric() || c == '@')(input)?;
+    let (input, name) = terminated(preceded(char('@'), identifier), boundary)(input)?;
     Ok((
         input,
         Token::LabelUsage {
@@ -110,10 +146,20 @@ pub fn parse_label_usage(input: &str) -> IResult<&str, Token> {
     ))
 }
 
+// Parser dla funkcji natywnych (np. !log)
+pub fn parse_native_usage(input: &str) -> IResult<&str, Token> {
+    let (input, name) = terminated(preceded(char('!'), identifier), boundary)(input)?;
+    Ok((
+        input,
+        Token::NativeUsage {
+            name: name.to_string(),
+        },
+    ))
+}
+
 // Parser dla dyrektyw (np. .section, .data)
 pub fn parse_directive(input: &str) -> IResult<&str, Token> {
-    let (input, _) = char('.')(input)?;
-    let (input, name) = alpha1(input)?;
+    let (input, name) = terminated(preceded(char('.'), identifier), boundary)(input)?;
 
     Ok((
         input,
@@ -125,9 +171,10 @@ pub fn parse_directive(input: &str) -> IResult<&str, Token> {
 
 // Parser dla stringów
 pub fn parse_string(input: &str) -> IResult<&str, Token> {
-    let (input, _) = char('"')(input)?;
-    let (input, content) = take_while1(|c| c != '"')(input)?;
-    let (input, _) = char('"')(input)?;
+    let (input, content) = terminated(
+        preceded(char('"'), terminated(take_while(|c| c != '"'), char('"'))),
+        boundary,
+    )(input)?;
 
     Ok((
         input,
@@ -140,6 +187,373 @@ pub fn parse_string(input: &str) -> IResult<&str, Token> {
 // Parser dla komentarzy (np. ; komentarz)
 pub fn parse_comment(input: &str) -> IResult<&str, Token> {
     let (input, _) = char(';')(input)?;
-    let (input, _) = take_while1(|c| c != '\n')(input)?;
+    let (input, _) = take_while(|c| c != '\n')(input)?;
     Ok((input, Token::Comment))
 }
+
+// Parser dla dowolnego tokenu, kolejność nie ma znaczenia, bo każdy parser
+// musi skończyć się na granicy tokenu
+pub fn parse_token(input: &str) -> IResult<&str, Token> {
+    alt((
+        parse_opcode,
+        parse_float_register,
+        parse_long_register,
+        parse_register,
+        parse_integer,
+        parse_float,
+        parse_label_declaration,
+        parse_label_usage,
+        parse_native_usage,
+        parse_directive,
+        parse_string,
+        parse_comment,
+    ))(input)
+}
+
+// Parser dla operandów instrukcji i dyrektyw
+pub fn parse_operand(input: &str) -> IResult<&str, Token> {
+    alt((
+        parse_float_register,
+        parse_long_register,
+        parse_register,
+        parse_label_usage,
+        parse_native_usage,
+        parse_string,
+        parse_float,
+        parse_integer,
+    ))(input)
+}
+
+// Parser dla jednej linii kodu: [etykieta:] [opcode|.dyrektywa [operandy...]] [; komentarz]
+pub fn parse_instruction(line: &str) -> IResult<&str, AsmInstruction> {
+    let consumed = |from: &str, to: &str| Span::of(line, &from[..from.len() - to.len()]);
+    let (input, _) = space0(line)?;
+    let (rest, label) = opt(parse_label_declaration)(input)?;
+    let label_span = consumed(input, rest);
+    let (head_start, _) = space0(rest)?;
+    let (mut input, head) = opt(alt((parse_opcode, parse_directive)))(head_start)?;
+    let head_span = consumed(head_start, input);
+    let mut operands = vec![];
+    let mut operand_spans = [Span::default(); 3];
+    while head.is_some() && operands.len() < 3 {
+        let Ok((start, _)) = space1::<_, ()>(input) else {
+            break;
+        };
+        let Ok((rest, operand)) = parse_operand(start) else {
+            break;
+        };
+        operand_spans[operands.len()] = consumed(start, rest);
+        operands.push(operand);
+        input = rest;
+    }
+    let (input, _) = space0(input)?;
+    let (input, _) = opt(parse_comment)(input)?;
+    let (input, _) = eof(input)?;
+
+    let (opcode, directive) = match head {
+        Some(Token::Directive { name }) => (None, Some(Token::Directive { name })),
+        opcode => (opcode, None),
+    };
+    let mut operands = operands.into_iter();
+    Ok((
+        input,
+        AsmInstruction {
+            opcode,
+            label,
+            directive,
+            operand1: operands.next(),
+            operand2: operands.next(),
+            operand3: operands.next(),
+            line: 0,
+            label_span,
+            head_span,
+            operand_spans,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::error::AsmErrorKind;
+    use crate::assembler::Assembler;
+    use crate::instruction::OPCODES;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        Assembler::new().tokenize(input).unwrap()
+    }
+    fn error(input: &str) -> (usize, AsmErrorKind) {
+        let error = Assembler::new().tokenize(input).unwrap_err();
+        (error.column, error.kind)
+    }
+    fn label(name: &str) -> Token {
+        Token::LabelDeclaration {
+            name: name.to_string(),
+        }
+    }
+    fn usage(name: &str) -> Token {
+        Token::LabelUsage {
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_every_mnemonic_is_matched_whole() {
+        for info in OPCODES {
+            for spelling in [info.mnemonic.to_string(), info.mnemonic.to_uppercase()] {
+                assert_eq!(
+                    parse_opcode(&spelling),
+                    Ok(("", Token::Op { code: info.opcode })),
+                    "{}",
+                    spelling
+                );
+                assert_eq!(
+                    tokens(&format!("{} ; comment", spelling)),
+                    vec![Token::Op { code: info.opcode }, Token::Comment]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_longest_mnemonic_wins() {
+        let cases = [
+            ("jmp", Opcode::JMP),
+            ("jmpf", Opcode::JMPF),
+            ("jmpeq", Opcode::JMPEQ),
+            ("jmpeqa", Opcode::JMPEQA),
+            ("jmpa", Opcode::JMPA),
+            ("jmpneq", Opcode::JMPNEQ),
+            ("jmpneqa", Opcode::JMPNEQA),
+            ("jmpb", Opcode::JMPB),
+            ("gt", Opcode::GT),
+            ("gtq", Opcode::GTQ),
+            ("lt", Opcode::LT),
+            ("ltq", Opcode::LTQ),
+            ("load", Opcode::LOAD),
+            ("loads", Opcode::LOADS),
+        ];
+        for (source, code) in cases {
+            assert_eq!(tokens(&format!("{} $1", source))[0], Token::Op { code });
+        }
+    }
+
+    #[test]
+    fn test_mnemonic_needs_a_boundary() {
+        for word in ["jmp2", "jmpx", "halt_now", "ltqq", "add$1"] {
+            assert!(parse_opcode(word).is_err(), "{}", word);
+        }
+        assert_eq!(
+            error("jmpeqz $1"),
+            (
+                1,
+                AsmErrorKind::UnknownMnemonic {
+                    name: "jmpeqz".to_string()
+                }
+            )
+        );
+        assert_eq!(
+            parse_opcode("halt;done"),
+            Ok((";done", Token::Op { code: Opcode::HLT }))
+        );
+    }
+
+    #[test]
+    fn test_label_declarations_and_usages_are_distinct() {
+        assert_eq!(
+            tokens("loop: jmp @loop"),
+            vec![
+                label("loop"),
+                Token::Op { code: Opcode::JMP },
+                usage("loop")
+            ]
+        );
+        // A mnemonic followed by a colon is a label, not an opcode.
+        assert_eq!(
+            tokens("halt: halt"),
+            vec![label("halt"), Token::Op { code: Opcode::HLT }]
+        );
+        assert_eq!(tokens("@_tmp1"), vec![usage("_tmp1")]);
+        assert_eq!(tokens("_start:"), vec![label("_start")]);
+        assert!(parse_label_declaration("@loop:").is_err());
+        assert!(parse_label_usage("@loop:").is_err());
+        assert!(parse_label_declaration("loop").is_err());
+        assert!(parse_label_declaration("1st:").is_err());
+        assert!(parse_label_declaration("a:b").is_err());
+        assert!(parse_label_usage("@").is_err());
+        assert!(parse_label_usage("loop").is_err());
+    }
+
+    #[test]
+    fn test_registers() {
+        assert_eq!(
+            tokens("$0 $31 $zero $sp $fp $ra"),
+            [0, 31, 0, 29, 30, 31]
+                .iter()
+                .map(|reg_num| Token::Register { reg_num: *reg_num })
+                .collect::<Vec<Token>>()
+        );
+        for bad in ["$32", "$1x", "$", "$$1", "$-1", "$sp2", "$f1"] {
+            assert!(parse_register(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            tokens("$f0 $f31 $fp"),
+            vec![
+                Token::FloatRegister { reg_num: 0 },
+                Token::FloatRegister { reg_num: 31 },
+                Token::Register { reg_num: 30 },
+            ]
+        );
+        for bad in ["$f32", "$f", "$f1x", "$fx"] {
+            assert!(parse_float_register(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            tokens("$l0 $l31"),
+            vec![
+                Token::LongRegister { reg_num: 0 },
+                Token::LongRegister { reg_num: 31 },
+            ]
+        );
+        for bad in ["$l32", "$l", "$l1x", "$lx"] {
+            assert!(parse_long_register(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(
+            tokens("0 -7 2147483648 -9223372036854775808 1.5 -0.25 1e300 2.5E-3 0.1"),
+            vec![
+                Token::IntegerOperand { value: 0 },
+                Token::IntegerOperand { value: -7 },
+                Token::IntegerOperand {
+                    value: i32::MAX as i64 + 1
+                },
+                Token::IntegerOperand { value: i64::MIN },
+                Token::FloatOperand { value: 1.5 },
+                Token::FloatOperand { value: -0.25 },
+                Token::FloatOperand { value: 1e300 },
+                Token::FloatOperand { value: 2.5e-3 },
+                Token::FloatOperand { value: 0.1 },
+            ]
+        );
+        for bad in ["12abc", "1.", "1.2.3", "--1", "-", "1e", "1.5e+", "e5"] {
+            assert!(
+                parse_integer(bad).is_err() && parse_float(bad).is_err(),
+                "{}",
+                bad
+            );
+        }
+        assert_eq!(
+            tokens("inf -inf"),
+            vec![
+                Token::FloatOperand {
+                    value: f64::INFINITY
+                },
+                Token::FloatOperand {
+                    value: f64::NEG_INFINITY
+                },
+            ]
+        );
+        assert!(matches!(tokens("NaN")[..], [Token::FloatOperand { value }] if value.is_nan()));
+        assert!(parse_float("infinity").is_err() && parse_float("nan").is_err());
+        assert_eq!(
+            error("loadf $f0 1e309"),
+            (
+                11,
+                AsmErrorKind::FloatOutOfRange {
+                    found: "1e309".to_string()
+                }
+            )
+        );
+        assert_eq!(
+            error("loadl $l1 9223372036854775808"),
+            (
+                11,
+                AsmErrorKind::IntegerOutOfRange {
+                    found: "9223372036854775808".to_string()
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_native_usage() {
+        assert_eq!(
+            tokens("!log !_x2"),
+            vec![
+                Token::NativeUsage {
+                    name: "log".to_string()
+                },
+                Token::NativeUsage {
+                    name: "_x2".to_string()
+                },
+            ]
+        );
+        for bad in ["!", "!1a", "!!a", "!a-b"] {
+            assert!(parse_native_usage(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_directives_strings_and_comments() {
+        assert_eq!(
+            tokens(".data\nmsg: .asciiz \"hi; there\" ; trailing"),
+            vec![
+                Token::Directive {
+                    name: "data".to_string()
+                },
+                label("msg"),
+                Token::Directive {
+                    name: "asciiz".to_string()
+                },
+                Token::IrString {
+                    name: "hi; there".to_string()
+                },
+                Token::Comment,
+            ]
+        );
+        assert_eq!(
+            tokens("\"\""),
+            vec![Token::IrString {
+                name: String::new()
+            }]
+        );
+        assert_eq!(tokens(";only a comment"), vec![Token::Comment]);
+        assert_eq!(tokens("  \n\t\n"), vec![]);
+        assert!(parse_string("\"a\"b").is_err());
+        assert!(parse_directive(".").is_err());
+        assert_eq!(
+            error("  .asciiz \"open"),
+            (11, AsmErrorKind::UnterminatedString)
+        );
+    }
+
+    #[test]
+    fn test_whole_program_tokenizes() {
+        let source = "main:\tload $1 10 ; counter\n  LOAD $2 @msg\nloop: sub $1 $3 $1\n  jmpeq @loop\n  halt";
+        assert_eq!(
+            tokens(source),
+            vec![
+                label("main"),
+                Token::Op { code: Opcode::LOAD },
+                Token::Register { reg_num: 1 },
+                Token::IntegerOperand { value: 10 },
+                Token::Comment,
+                Token::Op { code: Opcode::LOAD },
+                Token::Register { reg_num: 2 },
+                usage("msg"),
+                label("loop"),
+                Token::Op { code: Opcode::SUB },
+                Token::Register { reg_num: 1 },
+                Token::Register { reg_num: 3 },
+                Token::Register { reg_num: 1 },
+                Token::Op {
+                    code: Opcode::JMPEQ
+                },
+                usage("loop"),
+                Token::Op { code: Opcode::HLT },
+            ]
+        );
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Debug, Clone)]
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Symbol {
     pub name: String,
     pub offset: Option<u32>,
@@ -15,19 +17,28 @@ impl Symbol {
     pub fn new_with_offset(name: String, symbol_type: SymbolType, offset: u32) -> Symbol {
         Symbol {
             name,
-            offset: Some((offset)),
+            offset: Some(offset),
             symbol_type,
         }
     }
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SymbolType {
     Label,
     Integer,
     IrString,
 }
 
-#[derive(Debug, Clone, Default)]
+impl fmt::Display for SymbolType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolType::Label => write!(f, "code label"),
+            SymbolType::Integer | SymbolType::IrString => write!(f, "data label"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct SymbolTable {
     pub symbols: Vec<Symbol>,
 }
@@ -38,4 +49,33 @@ impl SymbolTable {
     pub fn add_symbol(&mut self, symbol: Symbol) {
         self.symbols.push(symbol);
     }
+    pub fn has_symbol(&self, name: &str) -> bool {
+        self.symbols.iter().any(|symbol| symbol.name == name)
+    }
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+    pub fn symbol_value(&self, name: &str) -> Option<u32> {
+        self.symbol(name).and_then(|symbol| symbol.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new_with_offset(
+            "test".to_string(),
+            SymbolType::Label,
+            12,
+        ));
+        symbols.add_symbol(Symbol::new("pending".to_string(), SymbolType::Label));
+        assert!(symbols.has_symbol("test"));
+        assert_eq!(symbols.symbol_value("test"), Some(12));
+        assert_eq!(symbols.symbol_value("pending"), None);
+        assert_eq!(symbols.symbol_value("missing"), None);
+    }
 }
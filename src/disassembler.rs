@@ -0,0 +1,411 @@
+//! Turns bytecode back into assembly.
+//!
+//! Decoding is driven by the opcode table, the same way the VM and the
+//! assembler use it. Opcodes keep the exact form they were encoded with
+//! (`LOADS`, `JMPA`, ...) so feeding `Listing::source` of assembler output
+//! back to the assembler reproduces the original bytes. Hand-encoded code may
+//! not: a 32-bit `LOAD` of a value that fits in 16 bits comes back as
+//! `LOADS`. Symbols, when the program carries them, give jump targets and
+//! data addresses their label names again.
+
+use std::fmt;
+
+use crate::assembler::symbol_table::{Symbol, SymbolTable, SymbolType};
+use crate::assembler::Program;
+use crate::instruction::{Opcode, OperandKind};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DisassemblyError {
+    IllegalOpcode {
+        offset: usize,
+        byte: u8,
+    },
+    /// The code ends in the middle of an instruction.
+    Truncated {
+        offset: usize,
+        opcode: Opcode,
+    },
+    /// Decoding was asked to start past the end of the code.
+    OutOfBounds {
+        offset: usize,
+    },
+}
+
+impl fmt::Display for DisassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisassemblyError::IllegalOpcode { offset, byte } => {
+                write!(f, "illegal opcode {} at {:#06x}", byte, offset)
+            }
+            DisassemblyError::Truncated { offset, opcode } => write!(
+                f,
+                "`{}` at {:#06x} is cut off by the end of the code",
+                opcode.mnemonic(),
+                offset
+            ),
+            DisassemblyError::OutOfBounds { offset } => {
+                write!(f, "offset {:#06x} is past the end of the code", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DisassemblyError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Operand {
+    Register(u8),
+    FloatRegister(u8),
+    LongRegister(u8),
+    Integer(i64),
+    Float(f64),
+    Address(u32),
+    Label(String),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "${}", register),
+            Operand::FloatRegister(register) => write!(f, "$f{}", register),
+            Operand::LongRegister(register) => write!(f, "$l{}", register),
+            Operand::Integer(value) => write!(f, "{}", value),
+            // `{:?}` keeps the decimal point and every digit of the value.
+            Operand::Float(value) => write!(f, "{:?}", value),
+            Operand::Address(address) => write!(f, "{}", address),
+            Operand::Label(name) => write!(f, "@{}", name),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DisassembledInstruction {
+    /// Offset of the opcode byte in the code.
+    pub offset: usize,
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+}
+
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.opcode.mnemonic().to_uppercase())?;
+        for operand in &self.operands {
+            write!(f, " {}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+/// A disassembled program. Its `Display` is the annotated listing with one
+/// `0x0004: ADD $1 $2 $3` line per instruction.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Listing {
+    pub instructions: Vec<DisassembledInstruction>,
+    /// Labels declared in the code, ordered by offset.
+    pub labels: Vec<(usize, String)>,
+    /// `.asciiz` and `.integer` entries rebuilt from the read-only data.
+    pub data: Vec<String>,
+}
+
+impl Listing {
+    /// The listing as assembler source, without the offsets.
+    pub fn source(&self) -> String {
+        let mut result = String::new();
+        if !self.data.is_empty() {
+            result.push_str(".data\n");
+            for entry in &self.data {
+                result.push_str(entry);
+                result.push('\n');
+            }
+            result.push_str(".code\n");
+        }
+        self.write_code(&mut result, false);
+        result
+    }
+    fn write_code(&self, result: &mut String, annotated: bool) {
+        let mut labels = self.labels.iter().peekable();
+        for instruction in &self.instructions {
+            while let Some((_, name)) = labels.next_if(|(offset, _)| *offset <= instruction.offset)
+            {
+                result.push_str(&format!("{}:\n", name));
+            }
+            if annotated {
+                result.push_str(&format!("{:#06x}: ", instruction.offset));
+            }
+            result.push_str(&format!("{}\n", instruction));
+        }
+        for (_, name) in labels {
+            result.push_str(&format!("{}:\n", name));
+        }
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut result = String::new();
+        self.write_code(&mut result, true);
+        write!(f, "{}", result)
+    }
+}
+
+/// Decodes a single instruction starting at `offset`.
+pub fn decode(
+    code: &[u8],
+    offset: usize,
+    symbols: &SymbolTable,
+) -> Result<DisassembledInstruction, DisassemblyError> {
+    let byte = *code
+        .get(offset)
+        .ok_or(DisassemblyError::OutOfBounds { offset })?;
+    let opcode = Opcode::from(byte);
+    if opcode == Opcode::IGL {
+        return Err(DisassemblyError::IllegalOpcode { offset, byte });
+    }
+    let info = opcode.info();
+    let bytes = code
+        .get(offset + 1..offset + info.size())
+        .ok_or(DisassemblyError::Truncated { offset, opcode })?;
+
+    let mut operands = vec![];
+    let mut position = 0;
+    for kind in info.operands {
+        let operand = &bytes[position..position + kind.size()];
+        position += kind.size();
+        operands.push(match kind {
+            OperandKind::Register => Operand::Register(operand[0]),
+            OperandKind::FloatRegister => Operand::FloatRegister(operand[0]),
+            OperandKind::LongRegister => Operand::LongRegister(operand[0]),
+            // Native names only exist in the VM the program runs on.
+            OperandKind::Native => {
+                Operand::Integer(u16::from_be_bytes([operand[0], operand[1]]) as i64)
+            }
+            OperandKind::Imm16 => {
+                Operand::Integer(i16::from_be_bytes([operand[0], operand[1]]) as i64)
+            }
+            OperandKind::Imm32 => {
+                let value = i32::from_be_bytes([operand[0], operand[1], operand[2], operand[3]]);
+                // The assembler only keeps the long form of a value this small
+                // when it came from a label, so the label has to come back.
+                match symbol_at(symbols, value as i64, false) {
+                    Some(symbol) if opcode == Opcode::LOAD && i16::try_from(value).is_ok() => {
+                        Operand::Label(symbol.name.clone())
+                    }
+                    _ => Operand::Integer(value as i64),
+                }
+            }
+            OperandKind::Imm64 => Operand::Integer(i64::from_be_bytes(operand.try_into().unwrap())),
+            OperandKind::F64 => Operand::Float(f64::from_bits(u64::from_be_bytes(
+                operand.try_into().unwrap(),
+            ))),
+            OperandKind::Address => {
+                let address = u32::from_be_bytes([operand[0], operand[1], operand[2], operand[3]]);
+                match symbol_at(symbols, address as i64, true) {
+                    Some(symbol) => Operand::Label(symbol.name.clone()),
+                    None => Operand::Address(address),
+                }
+            }
+        });
+    }
+    Ok(DisassembledInstruction {
+        offset,
+        opcode,
+        operands,
+    })
+}
+
+/// Disassembles raw code, naming addresses after the labels in `symbols`.
+pub fn disassemble(code: &[u8], symbols: &SymbolTable) -> Result<Listing, DisassemblyError> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < code.len() {
+        let instruction = decode(code, offset, symbols)?;
+        offset += instruction.opcode.info().size();
+        instructions.push(instruction);
+    }
+    let mut labels: Vec<(usize, String)> = symbols
+        .symbols
+        .iter()
+        .filter(|symbol| symbol.symbol_type == SymbolType::Label)
+        .filter_map(|symbol| Some((symbol.offset? as usize, symbol.name.clone())))
+        .collect();
+    labels.sort_by_key(|(offset, _)| *offset);
+    Ok(Listing {
+        instructions,
+        labels,
+        data: vec![],
+    })
+}
+
+/// Disassembles a whole program, including its `.data` section. Read-only
+/// data no symbol points at is listed as unnamed entries, so the named ones
+/// keep their addresses.
+pub fn disassemble_program(program: &Program) -> Result<Listing, DisassemblyError> {
+    let mut listing = disassemble(&program.code, &program.symbols)?;
+    let mut entries: Vec<(usize, &Symbol)> = program
+        .symbols
+        .symbols
+        .iter()
+        .filter(|symbol| symbol.symbol_type != SymbolType::Label)
+        .filter_map(|symbol| Some((symbol.offset? as usize, symbol)))
+        .collect();
+    entries.sort_by_key(|(offset, _)| *offset);
+    let mut entries = entries.into_iter().peekable();
+    let mut offset = 0;
+    while offset < program.ro_data.len() {
+        while entries.next_if(|(start, _)| *start < offset).is_some() {}
+        let named = entries.next_if(|(start, _)| *start == offset);
+        let end = entries
+            .peek()
+            .map_or(program.ro_data.len(), |(start, _)| *start);
+        let bytes = &program.ro_data[offset..end];
+        let (entry, size) = data_entry(bytes, named.map(|(_, symbol)| symbol.symbol_type));
+        listing.data.push(match named {
+            Some((_, symbol)) => format!("{}: {}", symbol.name, entry),
+            None => entry,
+        });
+        offset += size;
+    }
+    Ok(listing)
+}
+
+/// Rebuilds the directive for the data at the start of `bytes` and returns
+/// it with the number of bytes it covers. Unnamed data has no type, text
+/// ending in a NUL is taken for a string and anything else for integers.
+fn data_entry(bytes: &[u8], symbol_type: Option<SymbolType>) -> (String, usize) {
+    let text_end = bytes.iter().position(|byte| *byte == 0);
+    let integer = match symbol_type {
+        Some(symbol_type) => symbol_type == SymbolType::Integer,
+        None => !text_end.is_some_and(|end| {
+            std::str::from_utf8(&bytes[..end])
+                .is_ok_and(|text| !text.is_empty() && !text.chars().any(char::is_control))
+        }),
+    };
+    if integer && bytes.len() >= 4 {
+        let value = i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        return (format!(".integer {}", value), 4);
+    }
+    let end = text_end.unwrap_or(bytes.len());
+    let text = String::from_utf8_lossy(&bytes[..end]);
+    (format!(".asciiz \"{}\"", text), (end + 1).min(bytes.len()))
+}
+
+fn symbol_at(symbols: &SymbolTable, value: i64, labels_only: bool) -> Option<&Symbol> {
+    let mut candidates = symbols.symbols.iter().filter(|symbol| {
+        symbol.offset.map(i64::from) == Some(value)
+            && (!labels_only || symbol.symbol_type == SymbolType::Label)
+    });
+    let first = candidates.next()?;
+    // Data addresses are what `LOAD` usually takes, prefer those over labels.
+    Some(
+        std::iter::once(first)
+            .chain(candidates)
+            .find(|symbol| symbol.symbol_type != SymbolType::Label)
+            .unwrap_or(first),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn round_trip(source: &str) -> (Program, String) {
+        let program = Assembler::new().assemble(source).unwrap();
+        let listing = disassemble_program(&program).unwrap();
+        let reassembled = Assembler::new().assemble(&listing.source()).unwrap();
+        assert_eq!(reassembled, program, "listing:\n{}", listing.source());
+        (program, listing.to_string())
+    }
+
+    #[test]
+    fn test_annotated_listing() {
+        let (_, listing) = round_trip("load $1 500\nadd $1 $2 $3\nhalt");
+        assert_eq!(
+            listing,
+            "0x0000: LOADS $1 500\n0x0004: ADD $1 $2 $3\n0x0008: HALT\n"
+        );
+    }
+
+    #[test]
+    fn test_labels_come_back() {
+        let source = ".data\nname: .asciiz \"pecet\"\nanswer: .integer -42\n.code\nmain: load $1 @name\nload $2 @answer\nloop: eq $1 $2\njmpeq @loop\njmp @main\nend:";
+        let (program, listing) = round_trip(source);
+        assert_eq!(program.entry, 0);
+        assert_eq!(
+            listing,
+            "main:\n0x0000: LOAD $1 @name\n0x0006: LOAD $2 @answer\nloop:\n0x000c: EQ $1 $2\n0x000f: JMPEQA @loop\n0x0014: JMPA @main\nend:\n"
+        );
+    }
+
+    #[test]
+    fn test_unnamed_data_keeps_addresses() {
+        let source = ".data\n.integer 5\nx: .integer 7\n.asciiz \"hi\"\n.asciiz \"\"\ny: .asciiz \"yo\"\n.code\nload $1 @x\nload $2 @y";
+        let (program, _) = round_trip(source);
+        let listing = disassemble_program(&program).unwrap();
+        assert_eq!(
+            listing.data,
+            vec![
+                ".integer 5",
+                "x: .integer 7",
+                ".asciiz \"hi\"",
+                ".asciiz \"\"",
+                "y: .asciiz \"yo\""
+            ]
+        );
+    }
+
+    #[test]
+    fn test_every_opcode_round_trips() {
+        for info in crate::instruction::OPCODES {
+            if info.opcode == Opcode::IGL {
+                continue;
+            }
+            let mut code = vec![info.byte];
+            code.resize(info.size(), 3);
+            let listing = disassemble(&code, &SymbolTable::new()).unwrap();
+            let program = Assembler::new().assemble(&listing.source()).unwrap();
+            assert_eq!(program.code, code, "{}", listing);
+        }
+    }
+
+    #[test]
+    fn test_non_finite_floats_round_trip() {
+        let (program, listing) = round_trip("loadf $f0 inf\nloadf $f1 -inf\nloadf $f2 NaN");
+        assert_eq!(
+            listing,
+            "0x0000: LOADF $f0 inf\n0x000a: LOADF $f1 -inf\n0x0014: LOADF $f2 NaN\n"
+        );
+        assert_eq!(program.code[2..10], f64::INFINITY.to_be_bytes());
+    }
+
+    #[test]
+    fn test_hand_encoded_short_load_narrows() {
+        let code = [1, 0, 0, 0, 0, 5];
+        let listing = disassemble(&code, &SymbolTable::new()).unwrap();
+        assert_eq!(listing.source(), "LOAD $0 5\n");
+        let program = Assembler::new().assemble(&listing.source()).unwrap();
+        assert_eq!(program.code, vec![19, 0, 0, 5]);
+    }
+
+    #[test]
+    fn test_disassembly_errors() {
+        assert_eq!(
+            disassemble(&[0, 200], &SymbolTable::new()),
+            Err(DisassemblyError::IllegalOpcode {
+                offset: 1,
+                byte: 200
+            })
+        );
+        assert_eq!(
+            disassemble(&[2, 1, 2], &SymbolTable::new()),
+            Err(DisassemblyError::Truncated {
+                offset: 0,
+                opcode: Opcode::ADD
+            })
+        );
+        assert_eq!(
+            decode(&[0], 1, &SymbolTable::new()),
+            Err(DisassemblyError::OutOfBounds { offset: 1 })
+        );
+    }
+}
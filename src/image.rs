@@ -0,0 +1,273 @@
+//! On-disk executable format.
+//!
+//! An image is a fixed 24 byte header followed by the code segment, the
+//! read-only data segment and the symbol table, all numbers big-endian:
+//!
+//! | offset | size | field                               |
+//! |--------|------|-------------------------------------|
+//! | 0      | 4    | magic, `PCVM`                       |
+//! | 4      | 2    | format version, currently `1`       |
+//! | 6      | 2    | reserved, zero                      |
+//! | 8      | 4    | entry point, offset into code       |
+//! | 12     | 4    | code segment length                 |
+//! | 16     | 4    | read-only data segment length       |
+//! | 20     | 4    | symbol table section length         |
+//!
+//! The symbol table section is a `u32` count followed by that many entries
+//! of a type byte (`0` label, `1` integer, `2` string), a `u32` offset, a
+//! `u16` name length and the UTF-8 name.
+
+use std::fmt;
+
+use crate::assembler::symbol_table::{Symbol, SymbolTable, SymbolType};
+use crate::assembler::Program;
+
+pub const MAGIC: [u8; 4] = *b"PCVM";
+pub const VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 24;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ImageError {
+    BadMagic {
+        found: Vec<u8>,
+    },
+    UnsupportedVersion {
+        found: u16,
+    },
+    /// The image ends before the section the header announced.
+    Truncated {
+        section: &'static str,
+    },
+    BadSymbol {
+        index: usize,
+    },
+    EntryOutOfBounds {
+        entry: u32,
+        code_len: usize,
+    },
+    /// A symbol name longer than its `u16` length field can hold.
+    SymbolNameTooLong {
+        index: usize,
+        len: usize,
+    },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::BadMagic { found } => {
+                write!(f, "not a pecetVM image, magic is {:?}", found)
+            }
+            ImageError::UnsupportedVersion { found } => write!(
+                f,
+                "image format version {} is not supported, expected {}",
+                found, VERSION
+            ),
+            ImageError::Truncated { section } => write!(f, "image is truncated in the {}", section),
+            ImageError::BadSymbol { index } => {
+                write!(f, "symbol {} in the image is malformed", index)
+            }
+            ImageError::EntryOutOfBounds { entry, code_len } => write!(
+                f,
+                "entry point {} is outside of the {} byte code segment",
+                entry, code_len
+            ),
+            ImageError::SymbolNameTooLong { index, len } => write!(
+                f,
+                "symbol {} has a {} byte name, images allow at most {}",
+                index,
+                len,
+                u16::MAX
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// Encodes `program` as an image, failing only for a symbol name too long
+/// for the symbol table.
+pub fn write_image(program: &Program) -> Result<Vec<u8>, ImageError> {
+    let symbols = write_symbols(&program.symbols)?;
+    let mut result = Vec::with_capacity(
+        HEADER_SIZE + program.code.len() + program.ro_data.len() + symbols.len(),
+    );
+    result.extend(MAGIC);
+    result.extend(VERSION.to_be_bytes());
+    result.extend([0, 0]);
+    result.extend(program.entry.to_be_bytes());
+    result.extend((program.code.len() as u32).to_be_bytes());
+    result.extend((program.ro_data.len() as u32).to_be_bytes());
+    result.extend((symbols.len() as u32).to_be_bytes());
+    result.extend(&program.code);
+    result.extend(&program.ro_data);
+    result.extend(symbols);
+    Ok(result)
+}
+
+pub fn read_image(bytes: &[u8]) -> Result<Program, ImageError> {
+    if bytes.len() < 4 || bytes[0..4] != MAGIC {
+        return Err(ImageError::BadMagic {
+            found: bytes.iter().take(4).copied().collect(),
+        });
+    }
+    let mut reader = Reader { bytes, position: 4 };
+    let version = reader.u16("header")?;
+    if version != VERSION {
+        return Err(ImageError::UnsupportedVersion { found: version });
+    }
+    reader.take(2, "header")?;
+    let entry = reader.u32("header")?;
+    let code_len = reader.u32("header")? as usize;
+    let ro_len = reader.u32("header")? as usize;
+    let symbols_len = reader.u32("header")? as usize;
+
+    let code = reader.take(code_len, "code segment")?.to_vec();
+    let ro_data = reader.take(ro_len, "read-only data segment")?.to_vec();
+    let symbols = read_symbols(reader.take(symbols_len, "symbol table")?)?;
+    if entry as usize > code.len() {
+        return Err(ImageError::EntryOutOfBounds {
+            entry,
+            code_len: code.len(),
+        });
+    }
+    Ok(Program {
+        entry,
+        code,
+        ro_data,
+        symbols,
+    })
+}
+
+fn write_symbols(symbols: &SymbolTable) -> Result<Vec<u8>, ImageError> {
+    let symbols: Vec<(&Symbol, u32)> = symbols
+        .symbols
+        .iter()
+        .filter_map(|symbol| symbol.offset.map(|offset| (symbol, offset)))
+        .collect();
+    let mut result = vec![];
+    result.extend((symbols.len() as u32).to_be_bytes());
+    for (index, (symbol, offset)) in symbols.into_iter().enumerate() {
+        let len = u16::try_from(symbol.name.len()).map_err(|_| ImageError::SymbolNameTooLong {
+            index,
+            len: symbol.name.len(),
+        })?;
+        result.push(match symbol.symbol_type {
+            SymbolType::Label => 0,
+            SymbolType::Integer => 1,
+            SymbolType::IrString => 2,
+        });
+        result.extend(offset.to_be_bytes());
+        result.extend(len.to_be_bytes());
+        result.extend(symbol.name.as_bytes());
+    }
+    Ok(result)
+}
+
+fn read_symbols(bytes: &[u8]) -> Result<SymbolTable, ImageError> {
+    let mut table = SymbolTable::new();
+    if bytes.is_empty() {
+        return Ok(table);
+    }
+    let mut reader = Reader { bytes, position: 0 };
+    let count = reader.u32("symbol table")?;
+    for index in 0..count as usize {
+        let symbol_type = match reader.take(1, "symbol table")?[0] {
+            0 => SymbolType::Label,
+            1 => SymbolType::Integer,
+            2 => SymbolType::IrString,
+            _ => return Err(ImageError::BadSymbol { index }),
+        };
+        let offset = reader.u32("symbol table")?;
+        let name_len = reader.u16("symbol table")? as usize;
+        let name = String::from_utf8(reader.take(name_len, "symbol table")?.to_vec())
+            .map_err(|_| ImageError::BadSymbol { index })?;
+        table.add_symbol(Symbol::new_with_offset(name, symbol_type, offset));
+    }
+    Ok(table)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize, section: &'static str) -> Result<&'a [u8], ImageError> {
+        let end = self.position.saturating_add(len);
+        let result = self
+            .bytes
+            .get(self.position..end)
+            .ok_or(ImageError::Truncated { section })?;
+        self.position = end;
+        Ok(result)
+    }
+    fn u16(&mut self, section: &'static str) -> Result<u16, ImageError> {
+        let bytes = self.take(2, section)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+    fn u32(&mut self, section: &'static str) -> Result<u32, ImageError> {
+        let bytes = self.take(4, section)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn assembled() -> Program {
+        Assembler::new()
+            .assemble(".data\nname: .asciiz \"pecet\"\n.code\nhalt\nmain: load $1 @name\nhalt")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_image_round_trip() {
+        let program = assembled();
+        assert_eq!(program.entry, 1);
+        let image = write_image(&program).unwrap();
+        assert_eq!(&image[0..4], b"PCVM");
+        assert_eq!(&image[4..6], &[0, 1]);
+        assert_eq!(&image[8..12], &[0, 0, 0, 1]);
+        assert_eq!(read_image(&image), Ok(program));
+    }
+
+    #[test]
+    fn test_image_rejects_bad_header() {
+        let mut image = write_image(&assembled()).unwrap();
+        image[4..6].copy_from_slice(&[0, 9]);
+        assert_eq!(
+            read_image(&image),
+            Err(ImageError::UnsupportedVersion { found: 9 })
+        );
+        assert_eq!(
+            read_image(&[1, 2]),
+            Err(ImageError::BadMagic { found: vec![1, 2] })
+        );
+        let image = write_image(&assembled()).unwrap();
+        assert_eq!(
+            read_image(&image[..HEADER_SIZE + 3]),
+            Err(ImageError::Truncated {
+                section: "code segment"
+            })
+        );
+    }
+
+    #[test]
+    fn test_image_rejects_long_symbol_names() {
+        let mut program = assembled();
+        let name = "a".repeat(u16::MAX as usize + 1);
+        program
+            .symbols
+            .add_symbol(Symbol::new_with_offset(name, SymbolType::Label, 0));
+        assert_eq!(
+            write_image(&program),
+            Err(ImageError::SymbolNameTooLong {
+                index: 2,
+                len: u16::MAX as usize + 1
+            })
+        );
+    }
+}
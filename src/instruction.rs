@@ -1,54 +1,247 @@
-#[derive(Debug, PartialEq, Clone)]
-
-pub enum Opcode {
-    IGL,
-    HLT,
-    LOAD,
-    ADD,
-    SUB,
-    MUL,
-    DIV,
-    JMP,
-    JMPF,
-    EQ,
-    NEQ,
-    GT,
-    LT,
-    GTQ,
-    LTQ,
-    JMPEQ,
-    LABEL,
-    SQUARE,
-    ALLOC,
-    SET,
+//! Instruction encoding.
+//!
+//! Every instruction is one opcode byte followed by the operands declared
+//! for that opcode in `OPCODES`, in order and without padding, so the length
+//! of an instruction is fully determined by its opcode:
+//!
+//! | operand kind    | size    | encoding                             |
+//! |-----------------|---------|--------------------------------------|
+//! | `Register`      | 1 byte  | register index, `0..32`              |
+//! | `FloatRegister` | 1 byte  | float register index, `0..32`        |
+//! | `Imm16`         | 2 bytes | big-endian two's complement `i16`    |
+//! | `Imm32`         | 4 bytes | big-endian two's complement `i32`    |
+//! | `Address`       | 4 bytes | big-endian `u32` offset into program |
+//! | `F64`           | 8 bytes | big-endian IEEE 754 `f64` bits       |
+//! | `LongRegister`  | 1 byte  | long register index, `0..32`         |
+//! | `Imm64`         | 8 bytes | big-endian two's complement `i64`    |
+//! | `Native`        | 2 bytes | big-endian `u16` native index        |
+//!
+//! For example `ADD $1 $2 $3` is `[2, 1, 2, 3]` and `LOAD $1 70000` is
+//! `[1, 1, 0, 1, 17, 112]`. The assembler encodes `LOAD` of a value that
+//! fits in 16 bits as the shorter `LOADS`, so `LOAD $1 500` is
+//! `[19, 1, 1, 244]`. Likewise `JMP`, `JMPEQ` and `JMPNEQ` given a label or
+//! a number instead of a register are encoded as `JMPA`, `JMPEQA` and
+//! `JMPNEQA`.
+//!
+//! Byte 15 belonged to `LABEL`, a duplicate of `JMPEQ`. It is left unused so
+//! old programs that still contain it fail with an illegal opcode instead of
+//! running something else.
+
+use std::fmt;
+
+/// Number of general purpose registers, `$0` to `$31`.
+pub const REGISTER_COUNT: usize = 32;
+
+/// Stack pointer, the number of bytes currently pushed on the VM stack.
+pub const SP: u8 = 29;
+
+/// Names the assembler accepts in place of register numbers. Apart from
+/// `$sp`, which `PUSH`, `POP`, `CALL` and `RET` move, the VM gives them no
+/// special meaning, `$zero` is only zero by convention.
+pub const REGISTER_ALIASES: &[(&str, u8)] = &[("zero", 0), ("sp", SP), ("fp", 30), ("ra", 31)];
+
+/// Register index for an alias such as `sp`, without the `$`. Like
+/// mnemonics, aliases are matched ignoring case.
+pub fn register_alias(name: &str) -> Option<u8> {
+    REGISTER_ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+        .map(|(_, index)| *index)
+}
+
+/// Kind of a single operand following the opcode byte.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OperandKind {
+    /// Register index, one byte.
+    Register,
+    /// Signed 16-bit immediate, two bytes big-endian.
+    Imm16,
+    /// Signed 32-bit immediate, four bytes big-endian.
+    Imm32,
+    /// Absolute offset into the program, four bytes big-endian.
+    Address,
+    /// Index into the float register bank, one byte.
+    FloatRegister,
+    /// Float immediate, the eight bytes of its `f64` bits big-endian.
+    F64,
+    /// Index into the 64-bit integer register bank, one byte.
+    LongRegister,
+    /// Signed 64-bit immediate, eight bytes big-endian.
+    Imm64,
+    /// Index of a function registered with `VM::register_native`, two bytes
+    /// big-endian.
+    Native,
+}
+
+impl OperandKind {
+    pub fn size(self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister | OperandKind::LongRegister => 1,
+            OperandKind::Imm16 | OperandKind::Native => 2,
+            OperandKind::Imm32 | OperandKind::Address => 4,
+            OperandKind::F64 | OperandKind::Imm64 => 8,
+        }
+    }
 }
 
-impl From<u8> for Opcode {
-    fn from(value: u8) -> Self {
-        match value {
-            18 => return Opcode::SET,
-            17 => return Opcode::ALLOC,
-            16 => return Opcode::SQUARE,
-            15 => return Opcode::LABEL,
-            14 => return Opcode::JMPEQ,
-            13 => return Opcode::LTQ,
-            12 => return Opcode::GTQ,
-            11 => return Opcode::LT,
-            10 => return Opcode::GT,
-            9 => return Opcode::NEQ,
-            8 => return Opcode::EQ,
-            7 => return Opcode::JMPF,
-            6 => return Opcode::JMP,
-            5 => return Opcode::DIV,
-            4 => return Opcode::MUL,
-            3 => return Opcode::SUB,
-            2 => return Opcode::ADD,
-            1 => return Opcode::LOAD,
-            0 => return Opcode::HLT,
-            _ => return Opcode::IGL,
+impl fmt::Display for OperandKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OperandKind::Register => write!(f, "register"),
+            OperandKind::Imm16 => write!(f, "16-bit integer"),
+            OperandKind::Imm32 => write!(f, "32-bit integer"),
+            OperandKind::Address => write!(f, "label or address"),
+            OperandKind::FloatRegister => write!(f, "float register"),
+            OperandKind::F64 => write!(f, "float"),
+            OperandKind::LongRegister => write!(f, "long register"),
+            OperandKind::Imm64 => write!(f, "64-bit integer"),
+            OperandKind::Native => write!(f, "native function"),
         }
     }
 }
+
+/// Everything the VM, assembler and disassembler need to know about an opcode.
+#[derive(Debug, PartialEq)]
+pub struct OpcodeInfo {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    pub byte: u8,
+    pub operands: &'static [OperandKind],
+}
+
+impl OpcodeInfo {
+    /// Length of the encoded instruction in bytes, opcode included.
+    pub fn size(&self) -> usize {
+        1 + self.operands.iter().map(|kind| kind.size()).sum::<usize>()
+    }
+}
+
+macro_rules! opcodes {
+    ($($name:ident = $byte:literal, $mnemonic:literal, [$($operand:ident),*];)*) => {
+        #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+        #[allow(clippy::upper_case_acronyms)]
+        pub enum Opcode {
+            $($name,)*
+        }
+
+        /// The opcode table, every other mapping is derived from it.
+        pub const OPCODES: &[OpcodeInfo] = &[
+            $(OpcodeInfo {
+                opcode: Opcode::$name,
+                mnemonic: $mnemonic,
+                byte: $byte,
+                operands: &[$(OperandKind::$operand),*],
+            },)*
+        ];
+
+        impl From<u8> for Opcode {
+            fn from(value: u8) -> Self {
+                match value {
+                    $($byte => Opcode::$name,)*
+                    _ => Opcode::IGL,
+                }
+            }
+        }
+
+        impl From<Opcode> for u8 {
+            fn from(opcode: Opcode) -> Self {
+                match opcode {
+                    $(Opcode::$name => $byte,)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    HLT = 0, "halt", [];
+    LOAD = 1, "load", [Register, Imm32];
+    ADD = 2, "add", [Register, Register, Register];
+    SUB = 3, "sub", [Register, Register, Register];
+    MUL = 4, "mul", [Register, Register, Register];
+    DIV = 5, "div", [Register, Register, Register];
+    JMP = 6, "jmp", [Register];
+    JMPF = 7, "jmpf", [Register];
+    EQ = 8, "eq", [Register, Register];
+    NEQ = 9, "neq", [Register, Register];
+    GT = 10, "gt", [Register, Register];
+    LT = 11, "lt", [Register, Register];
+    GTQ = 12, "gtq", [Register, Register];
+    LTQ = 13, "ltq", [Register, Register];
+    JMPEQ = 14, "jmpeq", [Register];
+    SQUARE = 16, "square", [Register, Register];
+    ALLOC = 17, "alloc", [Register, Register];
+    SET = 18, "set", [Register];
+    LOADS = 19, "loads", [Register, Imm16];
+    JMPA = 20, "jmpa", [Address];
+    JMPEQA = 21, "jmpeqa", [Address];
+    PUSH = 22, "push", [Register];
+    POP = 23, "pop", [Register];
+    CALL = 24, "call", [Address];
+    RET = 25, "ret", [];
+    LOADB = 26, "loadb", [Register, Register, Imm16];
+    LOADW = 27, "loadw", [Register, Register, Imm16];
+    STOREB = 28, "storeb", [Register, Register, Imm16];
+    STOREW = 29, "storew", [Register, Register, Imm16];
+    FREE = 30, "free", [Register];
+    AND = 31, "and", [Register, Register, Register];
+    OR = 32, "or", [Register, Register, Register];
+    XOR = 33, "xor", [Register, Register, Register];
+    NOT = 34, "not", [Register, Register];
+    SHL = 35, "shl", [Register, Register, Register];
+    SHR = 36, "shr", [Register, Register, Register];
+    SAR = 37, "sar", [Register, Register, Register];
+    MOD = 38, "mod", [Register, Register, Register];
+    INC = 39, "inc", [Register];
+    DEC = 40, "dec", [Register];
+    JZ = 41, "jz", [Address];
+    JNZ = 42, "jnz", [Address];
+    JN = 43, "jn", [Address];
+    JO = 44, "jo", [Address];
+    LOADF = 45, "loadf", [FloatRegister, F64];
+    ADDF = 46, "addf", [FloatRegister, FloatRegister, FloatRegister];
+    SUBF = 47, "subf", [FloatRegister, FloatRegister, FloatRegister];
+    MULF = 48, "mulf", [FloatRegister, FloatRegister, FloatRegister];
+    DIVF = 49, "divf", [FloatRegister, FloatRegister, FloatRegister];
+    EQF = 50, "eqf", [FloatRegister, FloatRegister];
+    LTF = 51, "ltf", [FloatRegister, FloatRegister];
+    ITOF = 52, "itof", [Register, FloatRegister];
+    FTOI = 53, "ftoi", [FloatRegister, Register];
+    LOADL = 54, "loadl", [LongRegister, Imm64];
+    ADDL = 55, "addl", [LongRegister, LongRegister, LongRegister];
+    SUBL = 56, "subl", [LongRegister, LongRegister, LongRegister];
+    MULL = 57, "mull", [LongRegister, LongRegister, LongRegister];
+    DIVL = 58, "divl", [LongRegister, LongRegister, LongRegister];
+    EQL = 59, "eql", [LongRegister, LongRegister];
+    LTL = 60, "ltl", [LongRegister, LongRegister];
+    ITOL = 61, "itol", [Register, LongRegister];
+    LTOI = 62, "ltoi", [LongRegister, Register];
+    JMPNEQ = 63, "jmpneq", [Register];
+    JMPNEQA = 64, "jmpneqa", [Address];
+    JMPB = 65, "jmpb", [Register];
+    SYSCALL = 66, "syscall", [];
+    CALLN = 67, "calln", [Native, Register, Imm16];
+    IGL = 255, "igl", [];
+}
+
+impl Opcode {
+    pub fn info(self) -> &'static OpcodeInfo {
+        OPCODES
+            .iter()
+            .find(|info| info.opcode == self)
+            .expect("every opcode has an entry in OPCODES")
+    }
+    pub fn mnemonic(self) -> &'static str {
+        self.info().mnemonic
+    }
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        OPCODES
+            .iter()
+            .find(|info| info.mnemonic.eq_ignore_ascii_case(mnemonic))
+            .map(|info| info.opcode)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 
 pub struct Instruction {
@@ -57,7 +250,7 @@ pub struct Instruction {
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode: opcode }
+        Instruction { opcode }
     }
 }
 
@@ -74,4 +267,34 @@ mod tests {
         let instruction = Instruction::new(Opcode::HLT);
         assert_eq!(instruction.opcode, Opcode::HLT)
     }
+    #[test]
+    fn test_opcodes_round_trip() {
+        for info in OPCODES {
+            assert_eq!(Opcode::from(info.byte), info.opcode);
+            assert_eq!(u8::from(info.opcode), info.byte);
+            assert_eq!(Opcode::from_mnemonic(info.mnemonic), Some(info.opcode));
+            assert_eq!(info.opcode.info(), info);
+        }
+    }
+    #[test]
+    fn test_opcode_table_is_unique() {
+        for (i, a) in OPCODES.iter().enumerate() {
+            for b in &OPCODES[i + 1..] {
+                assert_ne!(a.byte, b.byte);
+                assert_ne!(a.mnemonic, b.mnemonic);
+            }
+        }
+    }
+    #[test]
+    fn test_unknown_byte_is_illegal() {
+        assert_eq!(Opcode::from(200), Opcode::IGL);
+        assert_eq!(Opcode::from_mnemonic("nope"), None);
+        assert_eq!(Opcode::from_mnemonic("JMPEQ"), Some(Opcode::JMPEQ));
+    }
+    #[test]
+    fn test_instruction_length() {
+        assert_eq!(Opcode::HLT.info().size(), 1);
+        assert_eq!(Opcode::LOAD.info().size(), 6);
+        assert_eq!(Opcode::ADD.info().size(), 4);
+    }
 }
@@ -0,0 +1,9 @@
+pub mod allocator;
+pub mod assembler;
+pub mod disassembler;
+pub mod image;
+pub mod instruction;
+pub mod observer;
+pub mod repl;
+pub mod syscall;
+pub mod vm;
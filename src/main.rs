@@ -1,12 +1,84 @@
-use instruction::Opcode;
-use nom::{bytes::complete::tag, IResult};
+use std::{env, fs, process};
 
-mod assembler;
-mod instruction;
-mod repl;
-mod vm;
+use pecet_vm::{
+    assembler::{Assembler, Program},
+    disassembler, image, repl,
+    vm::{ExitStatus, VM},
+};
 
+fn fail(message: String) -> ! {
+    eprintln!("***ERROR***\n{}", message);
+    process::exit(1);
+}
+
+/// `pecet-vm` starts the REPL, `pecet-vm <file>` runs an assembly source or
+/// an image, `pecet-vm <file.asm> -o <image>` writes the image instead and
+/// `pecet-vm <file> -d` prints its disassembly.
 fn main() {
-    let mut repl = repl::REPL::new();
-    repl.run();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let path = match args.first() {
+        Some(path) => path,
+        None => {
+            let mut repl = repl::REPL::new();
+            repl.run();
+            return;
+        }
+    };
+    let (output, disassemble) = match (args.get(1).map(String::as_str), args.get(2)) {
+        (Some("-o"), Some(output)) => (Some(output), false),
+        (Some("-d"), None) => (None, true),
+        (None, _) => (None, false),
+        _ => fail("usage: pecet-vm [<file> [-o <image> | -d]]".to_string()),
+    };
+    let bytes =
+        fs::read(path).unwrap_or_else(|error| fail(format!("unable to read {}: {}", path, error)));
+
+    let mut vm = VM::new();
+    if bytes.starts_with(&image::MAGIC) {
+        if output.is_some() {
+            fail(format!(
+                "{} is already an image, `-o` only takes an assembly source",
+                path
+            ));
+        }
+        if let Err(error) = vm.load_image(&bytes) {
+            fail(format!("{}: {}", path, error));
+        }
+    } else {
+        let source = String::from_utf8_lossy(&bytes);
+        let mut assembler = Assembler::new();
+        assembler.file = Some(path.clone());
+        let program = assembler.assemble(&source).unwrap_or_else(|diagnostics| {
+            for error in diagnostics {
+                eprintln!("{}\n", error);
+            }
+            process::exit(1);
+        });
+        if let Some(output) = output {
+            let image = image::write_image(&program)
+                .unwrap_or_else(|error| fail(format!("{}: {}", path, error)));
+            fs::write(output, image)
+                .unwrap_or_else(|error| fail(format!("unable to write {}: {}", output, error)));
+            return;
+        }
+        vm.load_program(program);
+    }
+    if disassemble {
+        let program = Program {
+            entry: vm.pc as u32,
+            code: vm.program,
+            ro_data: vm.ro_data,
+            symbols: vm.symbols,
+        };
+        match disassembler::disassemble_program(&program) {
+            Ok(listing) => print!("{}", listing.source()),
+            Err(error) => fail(format!("{}: {}", path, error)),
+        }
+        return;
+    }
+    match vm.run() {
+        Ok(ExitStatus::Exited(code)) => process::exit(code),
+        Ok(_) => {}
+        Err(error) => fail(error.to_string()),
+    }
 }
@@ -0,0 +1,94 @@
+use crate::instruction::Opcode;
+use crate::vm::VM;
+
+/// Hook into the interpreter loop of a `VM`.
+///
+/// Both callbacks get the address of the instruction and its opcode,
+/// `before_instruction` runs once the opcode is decoded and
+/// `after_instruction` once it executed without a fault. Observers are
+/// `Send` so a VM carrying one can still move to another thread.
+pub trait ExecutionObserver: Send {
+    fn before_instruction(&mut self, _vm: &VM, _pc: usize, _opcode: Opcode) {}
+    fn after_instruction(&mut self, _vm: &VM, _pc: usize, _opcode: Opcode) {}
+}
+
+/// Prints every executed instruction and the registers it changed to stdout.
+#[derive(Debug, Default)]
+pub struct TraceObserver {
+    registers: Vec<i32>,
+}
+
+impl TraceObserver {
+    pub fn new() -> TraceObserver {
+        TraceObserver { registers: vec![] }
+    }
+}
+
+impl ExecutionObserver for TraceObserver {
+    fn before_instruction(&mut self, vm: &VM, _pc: usize, _opcode: Opcode) {
+        self.registers = vm.registers.to_vec();
+    }
+    fn after_instruction(&mut self, vm: &VM, pc: usize, opcode: Opcode) {
+        print!("[{:#06x}] {:?}", pc, opcode);
+        for (i, (old, new)) in self.registers.iter().zip(vm.registers.iter()).enumerate() {
+            if old != new {
+                print!(" ${}: {} -> {}", i, old, new);
+            }
+        }
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Events = Arc<Mutex<Vec<(&'static str, usize, Opcode)>>>;
+
+    struct RecordingObserver {
+        events: Events,
+    }
+
+    impl ExecutionObserver for RecordingObserver {
+        fn before_instruction(&mut self, _vm: &VM, pc: usize, opcode: Opcode) {
+            self.events.lock().unwrap().push(("before", pc, opcode));
+        }
+        fn after_instruction(&mut self, _vm: &VM, pc: usize, opcode: Opcode) {
+            self.events.lock().unwrap().push(("after", pc, opcode));
+        }
+    }
+
+    #[test]
+    fn test_observer_sees_every_instruction() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut test_vm = VM::new();
+        test_vm.observer = Some(Box::new(RecordingObserver {
+            events: events.clone(),
+        }));
+        test_vm.program = vec![1, 0, 0, 0, 0, 5, 0];
+        test_vm.run().unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ("before", 0, Opcode::LOAD),
+                ("after", 0, Opcode::LOAD),
+                ("before", 6, Opcode::HLT),
+                ("after", 6, Opcode::HLT),
+            ]
+        );
+        assert!(test_vm.observer.is_some());
+    }
+
+    #[test]
+    fn test_observer_skips_after_on_fault() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut test_vm = VM::new();
+        test_vm.observer = Some(Box::new(RecordingObserver {
+            events: events.clone(),
+        }));
+        test_vm.program = vec![5, 0, 1, 2];
+        assert!(test_vm.run().is_err());
+        assert_eq!(*events.lock().unwrap(), vec![("before", 0, Opcode::DIV)]);
+    }
+}
@@ -1,10 +1,10 @@
 use crate::assembler::Assembler;
-use crate::instruction::Opcode;
-use crate::vm::VM;
+use crate::disassembler;
+use crate::observer::TraceObserver;
+use crate::vm::{HEAP_BASE, VM};
 use std;
 use std::io;
 use std::io::Write;
-use std::num::ParseIntError;
 
 pub struct REPL {
     command_buffer: Vec<String>,
@@ -12,6 +12,12 @@ pub struct REPL {
     assembler: Assembler,
 }
 
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl REPL {
     pub fn new() -> REPL {
         REPL {
@@ -21,22 +27,6 @@ impl REPL {
         }
     }
 
-    fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
-        let split = i.split(" ").collect::<Vec<&str>>();
-        let mut results: Vec<u8> = vec![];
-        for hex_string in split {
-            let byte = u8::from_str_radix(&hex_string, 16);
-            match byte {
-                Ok(result) => {
-                    results.push(result);
-                }
-                Err(e) => {
-                    return Err(e);
-                }
-            }
-        }
-        Ok(results)
-    }
     pub fn run(&mut self) {
         println!("[👋] Welcome to the pecetVM🖥️ REPL");
         println!("[ℹ️] This is open source project, founded by Jakub Pacewicz in 2024");
@@ -63,21 +53,54 @@ impl REPL {
                         if i % 4 != 0 {
                             print!(" [R{}]{}", i, register);
                         } else {
-                            print!(" [R{}]{}\n", i, register);
+                            println!(" [R{}]{}", i, register);
                         }
 
                         i += 1;
                     }
                     if trimmed_buffer.len() < i + 1 {
-                        print!("\n");
+                        println!();
+                    }
+                }
+                ".fregisters" => {
+                    println!("Current state of float registers:");
+                    for (i, register) in self.vm.float_registers.iter().enumerate() {
+                        print!(" [F{}]{:?}", i, register);
+                        if i % 4 == 3 {
+                            println!();
+                        }
+                    }
+                }
+                ".lregisters" => {
+                    println!("Current state of long registers:");
+                    for (i, register) in self.vm.long_registers.iter().enumerate() {
+                        print!(" [L{}]{}", i, register);
+                        if i % 4 == 3 {
+                            println!();
+                        }
                     }
                 }
+                ".flags" => {
+                    let flags = self.vm.flags;
+                    println!(
+                        "Flags: zero={} negative={} carry={} overflow={} ({:?} overflow)",
+                        flags.zero,
+                        flags.negative,
+                        flags.carry,
+                        flags.overflow,
+                        self.vm.overflow_mode
+                    );
+                }
                 ".pc" => {
                     println!("Current Program Counter: {:?}", self.vm.pc);
                 }
                 ".program" => {
                     println!("Current program vector:");
-                    println!("{:?}", self.vm.program)
+                    println!("{:?}", self.vm.program);
+                    match disassembler::disassemble(&self.vm.program, &self.vm.symbols) {
+                        Ok(listing) => print!("{}", listing),
+                        Err(error) => println!("***ERROR***\n{}", error),
+                    }
                 }
                 ".history" => {
                     for command in &self.command_buffer {
@@ -85,21 +108,101 @@ impl REPL {
                     }
                 }
                 ".heap" => {
-                    println!("Current Program Heap: {:?}", self.vm.heap);
+                    let stats = self.vm.allocator.stats();
+                    println!(
+                        "Heap: {} bytes, {} used in {} live blocks, {} free in {} blocks, {:.0}% fragmented",
+                        self.vm.heap.len(),
+                        stats.used_bytes,
+                        stats.live_blocks,
+                        stats.free_bytes,
+                        stats.free_blocks,
+                        stats.fragmentation() * 100.0
+                    );
+                    for block in self.vm.allocator.blocks() {
+                        println!(
+                            " {:#010x} {:>8} bytes {}",
+                            HEAP_BASE as usize + block.offset,
+                            block.size,
+                            if block.free { "free" } else { "live" }
+                        );
+                    }
+                }
+                ".trace" => {
+                    if self.vm.observer.take().is_some() {
+                        println!("Tracing disabled");
+                    } else {
+                        self.vm.observer = Some(Box::new(TraceObserver::new()));
+                        println!("Tracing enabled");
+                    }
                 }
                 ".quit" => {
                     println!("[🛑] pecetVM has been finished the program\nGoodbye!👋");
                     std::process::exit(0);
                 }
-                _ => {
-                    let (res, tokens) = self.assembler.tokenize(&buffer.trim()).unwrap();
-                    let program = self.assembler.compile(tokens).unwrap();
-                    for byte in program {
-                        self.vm.add_byte(byte)
-                    }
-                    self.vm.run_once_write_everywhere();
+                _ => self.execute(trimmed_buffer),
+            }
+        }
+    }
+    /// Assembles `source` after the code already in the VM and runs it.
+    fn execute(&mut self, source: &str) {
+        let program = match self.assembler.assemble_at(
+            source,
+            self.vm.program.len() as u32,
+            self.vm.ro_data.len() as u32,
+            self.vm.symbols.clone(),
+        ) {
+            Ok(program) => program,
+            Err(diagnostics) => {
+                for error in diagnostics {
+                    println!("{}\n", error);
                 }
+                return;
             }
+        };
+        let code_len = self.vm.program.len();
+        let data_len = self.vm.ro_data.len();
+        let symbols = std::mem::replace(&mut self.vm.symbols, program.symbols);
+        self.vm.program.extend(program.code);
+        self.vm.ro_data.extend(program.ro_data);
+        if let Err(error) = self.vm.run() {
+            println!("***ERROR***\n{}", error);
+            // Drop the faulting line so the next one starts after the
+            // code that ran fine.
+            self.vm.program.truncate(code_len);
+            self.vm.ro_data.truncate(data_len);
+            self.vm.symbols = symbols;
+            self.vm.pc = code_len;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels_on_later_lines() {
+        let mut repl = REPL::new();
+        repl.execute("load $1 7");
+        repl.execute("here: load $2 @here");
+        assert_eq!(repl.vm.symbols.symbol_value("here"), Some(4));
+        assert_eq!(repl.vm.registers[2], 4);
+        repl.execute("load $3 @here");
+        assert_eq!(repl.vm.registers[3], 4);
+        repl.execute("here: halt");
+        assert_eq!(repl.vm.program.len(), 16);
+    }
+    #[test]
+    fn test_fault_drops_the_line() {
+        let mut repl = REPL::new();
+        repl.execute("load $1 7");
+        repl.execute("fault: div $1 $zero $2");
+        assert_eq!(repl.vm.program.len(), 4);
+        assert_eq!(repl.vm.pc, 4);
+        assert!(!repl.vm.symbols.has_symbol("fault"));
+        repl.execute("fault: add $1 $1 $3");
+        assert_eq!(repl.vm.registers[3], 14);
+        assert_eq!(repl.vm.symbols.symbol_value("fault"), Some(4));
+        assert_eq!(repl.vm.pc, 8);
+    }
+}
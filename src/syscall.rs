@@ -0,0 +1,61 @@
+//! Host services a program reaches with `SYSCALL`.
+//!
+//! The service number is read from `$1` and its argument, if it takes one,
+//! from `$2`. Results come back in the same registers:
+//!
+//! | number | service        | arguments                 | results                          |
+//! |--------|----------------|---------------------------|----------------------------------|
+//! | 0      | `Exit`         | `$2` exit code            |                                  |
+//! | 1      | `PrintInt`     | `$2` value                |                                  |
+//! | 2      | `PrintString`  | `$2` address of a string  |                                  |
+//! | 3      | `ReadLine`     |                           | `$1` address, `$2` length        |
+//!
+//! Strings are NUL terminated and may live in the read-only data or on the
+//! heap. `ReadLine` allocates a heap block for the line, without its line
+//! break, and sets `$2` to -1 instead once the input has ended. The console
+//! is `VM::input` and `VM::output`, stdin and stdout unless replaced.
+
+/// Register holding the service number.
+pub const SYSCALL_REGISTER: u8 = 1;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Syscall {
+    Exit,
+    PrintInt,
+    PrintString,
+    ReadLine,
+}
+
+impl Syscall {
+    pub fn from_number(number: i32) -> Option<Syscall> {
+        match number {
+            0 => Some(Syscall::Exit),
+            1 => Some(Syscall::PrintInt),
+            2 => Some(Syscall::PrintString),
+            3 => Some(Syscall::ReadLine),
+            _ => None,
+        }
+    }
+    pub fn number(self) -> i32 {
+        match self {
+            Syscall::Exit => 0,
+            Syscall::PrintInt => 1,
+            Syscall::PrintString => 2,
+            Syscall::ReadLine => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbers_round_trip() {
+        for number in 0..4 {
+            assert_eq!(Syscall::from_number(number).unwrap().number(), number);
+        }
+        assert_eq!(Syscall::from_number(4), None);
+        assert_eq!(Syscall::from_number(-1), None);
+    }
+}
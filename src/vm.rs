@@ -1,241 +1,1213 @@
-use crate::instruction::Opcode;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::allocator::Allocator;
+use crate::assembler::symbol_table::SymbolTable;
+use crate::assembler::Program;
+use crate::image::{self, ImageError};
+use crate::instruction::{Opcode, REGISTER_COUNT, SP};
+use crate::observer::ExecutionObserver;
+use crate::syscall::{Syscall, SYSCALL_REGISTER};
+
+pub const DEFAULT_HEAP_LIMIT: usize = 1024 * 1024;
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+/// Address of the first heap byte. `LOADB`, `LOADW`, `STOREB` and `STOREW`
+/// see one address space: read-only data from 0, so data labels can be used
+/// as addresses directly, and the heap from here on.
+pub const HEAP_BASE: u32 = 0x1000_0000;
+
+/// Host function `CALLN` calls, see `VM::register_native`. It gets the VM
+/// and the argument registers and returns the value for the first of them,
+/// or a message that faults the program.
+pub type NativeFunction = Box<dyn FnMut(&mut VM, &[i32]) -> Result<i32, String> + Send>;
+
+/// `VM::register_native` ran out of indexes, `CALLN` can only address
+/// 65536 native functions.
+#[derive(Debug, PartialEq, Clone)]
+pub struct NativeTableFull {
+    pub name: String,
+}
+
+impl fmt::Display for NativeTableFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot register native `{}`, all {} indexes are taken",
+            self.name,
+            u16::MAX as usize + 1
+        )
+    }
+}
+
+impl std::error::Error for NativeTableFull {}
+
+struct Native {
+    name: String,
+    // Taken out while the function runs, so it can be given the VM.
+    function: Option<NativeFunction>,
+}
+
+/// Condition flags set by arithmetic and bitwise instructions.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Flags {
+    /// The result was zero.
+    pub zero: bool,
+    /// The result was negative.
+    pub negative: bool,
+    /// Unsigned carry out of an addition or borrow of a subtraction.
+    /// `MUL`, `SQUARE` and `MULL` set it together with `overflow`, when the
+    /// signed product does not fit. Division and bitwise instructions clear
+    /// it.
+    pub carry: bool,
+    /// The signed result did not fit in 32 bits.
+    pub overflow: bool,
+}
+
+/// What signed arithmetic overflow does.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OverflowMode {
+    /// The result wraps around and the overflow flag is set.
+    #[default]
+    Wrapping,
+    /// The instruction faults with `VmError::ArithmeticOverflow`.
+    Trapping,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExitStatus {
+    Halted,
+    EndOfProgram,
+    /// The program made the `Exit` syscall with this code.
+    Exited(i32),
+}
+
+/// Result of executing a single instruction with `VM::step`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum StepOutcome {
+    Continue,
+    Halted(ExitStatus),
+    Faulted(VmError),
+    /// The instruction budget given to `VM::run_for` ran out before the
+    /// program finished, it can be resumed by calling it again.
+    Yielded,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+    IllegalOpcode {
+        pc: usize,
+        byte: u8,
+    },
+    PcOutOfBounds {
+        pc: usize,
+        opcode: Opcode,
+        address: i64,
+    },
+    BadRegister {
+        pc: usize,
+        opcode: Opcode,
+        register: u8,
+    },
+    DivideByZero {
+        pc: usize,
+        opcode: Opcode,
+    },
+    /// Signed overflow with `OverflowMode::Trapping`.
+    ArithmeticOverflow {
+        pc: usize,
+        opcode: Opcode,
+    },
+    HeapOverflow {
+        pc: usize,
+        opcode: Opcode,
+        requested: i64,
+    },
+    /// A push with `$sp` at the end of the stack region.
+    StackOverflow {
+        pc: usize,
+        opcode: Opcode,
+        sp: i32,
+    },
+    /// A load or store outside of the read-only data and the heap, or a
+    /// store into the read-only data.
+    MemoryFault {
+        pc: usize,
+        opcode: Opcode,
+        address: i64,
+        write: bool,
+    },
+    /// `FREE` of a pointer `ALLOC` did not return, or one already freed.
+    InvalidFree {
+        pc: usize,
+        opcode: Opcode,
+        pointer: i64,
+    },
+    /// A pop with nothing on the stack, or `$sp` pointing outside of it.
+    StackUnderflow {
+        pc: usize,
+        opcode: Opcode,
+        sp: i32,
+    },
+    /// `SYSCALL` with a service number that does not exist.
+    UnknownSyscall {
+        pc: usize,
+        opcode: Opcode,
+        number: i32,
+    },
+    /// Reading `VM::input` or writing `VM::output` failed.
+    Io {
+        pc: usize,
+        opcode: Opcode,
+        kind: io::ErrorKind,
+    },
+    /// `CALLN` of an index no native function was registered under.
+    UnknownNative {
+        pc: usize,
+        opcode: Opcode,
+        index: u16,
+    },
+    /// `CALLN` with a negative argument count, or one reaching past `$31`.
+    BadArgumentCount {
+        pc: usize,
+        opcode: Opcode,
+        first: u8,
+        count: i16,
+    },
+    /// A native function returned an error, or was called while running.
+    NativeFailed {
+        pc: usize,
+        opcode: Opcode,
+        name: String,
+        message: String,
+    },
+}
+
+impl VmError {
+    pub fn pc(&self) -> usize {
+        match self {
+            VmError::IllegalOpcode { pc, .. }
+            | VmError::PcOutOfBounds { pc, .. }
+            | VmError::BadRegister { pc, .. }
+            | VmError::DivideByZero { pc, .. }
+            | VmError::ArithmeticOverflow { pc, .. }
+            | VmError::HeapOverflow { pc, .. }
+            | VmError::StackOverflow { pc, .. }
+            | VmError::StackUnderflow { pc, .. }
+            | VmError::MemoryFault { pc, .. }
+            | VmError::InvalidFree { pc, .. }
+            | VmError::UnknownSyscall { pc, .. }
+            | VmError::Io { pc, .. }
+            | VmError::UnknownNative { pc, .. }
+            | VmError::BadArgumentCount { pc, .. }
+            | VmError::NativeFailed { pc, .. } => *pc,
+        }
+    }
+    pub fn opcode(&self) -> Option<Opcode> {
+        match self {
+            VmError::IllegalOpcode { .. } => None,
+            VmError::PcOutOfBounds { opcode, .. }
+            | VmError::BadRegister { opcode, .. }
+            | VmError::DivideByZero { opcode, .. }
+            | VmError::ArithmeticOverflow { opcode, .. }
+            | VmError::HeapOverflow { opcode, .. }
+            | VmError::StackOverflow { opcode, .. }
+            | VmError::StackUnderflow { opcode, .. }
+            | VmError::MemoryFault { opcode, .. }
+            | VmError::InvalidFree { opcode, .. }
+            | VmError::UnknownSyscall { opcode, .. }
+            | VmError::Io { opcode, .. }
+            | VmError::UnknownNative { opcode, .. }
+            | VmError::BadArgumentCount { opcode, .. }
+            | VmError::NativeFailed { opcode, .. } => Some(*opcode),
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::IllegalOpcode { pc, byte } => {
+                write!(f, "illegal opcode {} at pc {}", byte, pc)
+            }
+            VmError::PcOutOfBounds {
+                pc,
+                opcode,
+                address,
+            } => write!(
+                f,
+                "{:?} at pc {} reached address {} outside of the program",
+                opcode, pc, address
+            ),
+            VmError::BadRegister {
+                pc,
+                opcode,
+                register,
+            } => write!(
+                f,
+                "{:?} at pc {} used bad register ${}",
+                opcode, pc, register
+            ),
+            VmError::DivideByZero { pc, opcode } => {
+                write!(f, "{:?} at pc {} divided by zero", opcode, pc)
+            }
+            VmError::ArithmeticOverflow { pc, opcode } => {
+                write!(f, "{:?} at pc {} overflowed", opcode, pc)
+            }
+            VmError::HeapOverflow {
+                pc,
+                opcode,
+                requested,
+            } => write!(
+                f,
+                "{:?} at pc {} requested {} bytes of heap which does not fit",
+                opcode, pc, requested
+            ),
+            VmError::StackOverflow { pc, opcode, sp } => write!(
+                f,
+                "{:?} at pc {} overflowed the stack, $sp is {}",
+                opcode, pc, sp
+            ),
+            VmError::MemoryFault {
+                pc,
+                opcode,
+                address,
+                write,
+            } => write!(
+                f,
+                "{:?} at pc {} {} address {:#x} which is {}",
+                opcode,
+                pc,
+                if *write { "wrote to" } else { "read from" },
+                address,
+                if *write && *address >= 0 && *address < HEAP_BASE as i64 {
+                    "read-only"
+                } else {
+                    "not mapped"
+                }
+            ),
+            VmError::InvalidFree {
+                pc,
+                opcode,
+                pointer,
+            } => write!(
+                f,
+                "{:?} at pc {} freed {:#x} which is not an allocated block",
+                opcode, pc, pointer
+            ),
+            VmError::StackUnderflow { pc, opcode, sp } => write!(
+                f,
+                "{:?} at pc {} underflowed the stack, $sp is {}",
+                opcode, pc, sp
+            ),
+            VmError::UnknownSyscall { pc, opcode, number } => {
+                write!(
+                    f,
+                    "{:?} at pc {} called unknown service {}",
+                    opcode, pc, number
+                )
+            }
+            VmError::Io { pc, opcode, kind } => {
+                write!(f, "{:?} at pc {} failed to do I/O: {}", opcode, pc, kind)
+            }
+            VmError::UnknownNative { pc, opcode, index } => write!(
+                f,
+                "{:?} at pc {} called native {} which is not registered",
+                opcode, pc, index
+            ),
+            VmError::BadArgumentCount {
+                pc,
+                opcode,
+                first,
+                count,
+            } => write!(
+                f,
+                "{:?} at pc {} passed {} arguments starting at ${}, which do not fit in the registers",
+                opcode, pc, count, first
+            ),
+            VmError::NativeFailed {
+                pc,
+                opcode,
+                name,
+                message,
+            } => write!(
+                f,
+                "{:?} at pc {} called native `{}` which failed: {}",
+                opcode, pc, name, message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
 
 pub struct VM {
-    pub registers: [i32; 32],
+    pub registers: [i32; REGISTER_COUNT],
+    /// Separate bank used by the `*F` opcodes, `$f0` to `$f31` in assembly.
+    pub float_registers: [f64; REGISTER_COUNT],
+    /// 64-bit integer bank used by the `*L` opcodes, `$l0` to `$l31` in
+    /// assembly. The 32-bit registers and opcodes are not affected by it.
+    pub long_registers: [i64; REGISTER_COUNT],
     pub pc: usize,
     pub program: Vec<u8>,
     pub remainder: u32,
     pub equal_flag: bool,
+    pub flags: Flags,
+    pub overflow_mode: OverflowMode,
     pub heap: Vec<u8>,
+    pub heap_limit: usize,
+    /// Tracks which parts of `heap` are allocated.
+    pub allocator: Allocator,
     pub ro_data: Vec<u8>,
+    /// Stack region, `$sp` counts the bytes pushed on it. Values are pushed
+    /// as four big-endian bytes and the stack grows towards higher offsets.
+    pub stack: Vec<u8>,
+    /// Symbols of the loaded program, only used for debugging output.
+    pub symbols: SymbolTable,
+    pub observer: Option<Box<dyn ExecutionObserver>>,
+    /// Console read by the `ReadLine` syscall, stdin by default.
+    pub input: Box<dyn Read + Send>,
+    /// Console written by the print syscalls, stdout by default.
+    pub output: Box<dyn Write + Send>,
+    natives: Vec<Native>,
+    // Address and opcode of the instruction currently being executed,
+    // used to report where a fault happened.
+    instruction_pc: usize,
+    instruction_opcode: Opcode,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            registers: [0; 32],
+            registers: [0; REGISTER_COUNT],
+            float_registers: [0.0; REGISTER_COUNT],
+            long_registers: [0; REGISTER_COUNT],
             pc: 0,
             program: vec![],
             remainder: 0,
             equal_flag: false,
+            flags: Flags::default(),
+            overflow_mode: OverflowMode::Wrapping,
             heap: vec![],
+            heap_limit: DEFAULT_HEAP_LIMIT,
+            allocator: Allocator::new(),
             ro_data: vec![],
+            stack: vec![0; DEFAULT_STACK_SIZE],
+            symbols: SymbolTable::new(),
+            observer: None,
+            input: Box::new(io::stdin()),
+            output: Box::new(io::stdout()),
+            natives: vec![],
+            instruction_pc: 0,
+            instruction_opcode: Opcode::IGL,
         }
     }
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+    /// Makes `function` callable from bytecode as `CALLN !name` and returns
+    /// its index. Registering a name again replaces the function and keeps
+    /// the index, indexes follow the order of registration.
+    pub fn register_native<F>(&mut self, name: &str, function: F) -> Result<u16, NativeTableFull>
+    where
+        F: FnMut(&mut VM, &[i32]) -> Result<i32, String> + Send + 'static,
+    {
+        let native = Native {
+            name: name.to_string(),
+            function: Some(Box::new(function)),
+        };
+        if let Some(index) = self.natives.iter().position(|native| native.name == name) {
+            self.natives[index] = native;
+            return Ok(index as u16);
+        }
+        let index = u16::try_from(self.natives.len()).map_err(|_| NativeTableFull {
+            name: name.to_string(),
+        })?;
+        self.natives.push(native);
+        Ok(index)
+    }
+    /// Names of the registered native functions by index, for
+    /// `Assembler::natives`.
+    pub fn native_names(&self) -> Vec<String> {
+        self.natives
+            .iter()
+            .map(|native| native.name.clone())
+            .collect()
     }
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
+    fn call_native(&mut self) -> Result<(), VmError> {
+        let index = u16::from_be_bytes(self.next_bytes()?);
+        let first = self.next_register()?;
+        let count = self.next_16_bits()?;
+        let arguments = usize::try_from(count)
+            .ok()
+            .and_then(|count| self.registers.get(first..first + count))
+            .ok_or(VmError::BadArgumentCount {
+                pc: self.instruction_pc,
+                opcode: self.instruction_opcode,
+                first: first as u8,
+                count,
+            })?
+            .to_vec();
+        let native = self
+            .natives
+            .get_mut(index as usize)
+            .ok_or(VmError::UnknownNative {
+                pc: self.instruction_pc,
+                opcode: self.instruction_opcode,
+                index,
+            })?;
+        let (pc, opcode) = (self.instruction_pc, self.instruction_opcode);
+        let name = native.name.clone();
+        let failed = |message: String| VmError::NativeFailed {
+            pc,
+            opcode,
+            name: name.clone(),
+            message,
+        };
+        let Some(mut function) = native.function.take() else {
+            return Err(failed("it is already running".to_string()));
+        };
+        let result = function(self, &arguments);
+        // A function that runs more bytecode leaves another instruction here.
+        (self.instruction_pc, self.instruction_opcode) = (pc, opcode);
+        // The function may have registered a replacement for itself.
+        if let Some(native) = self.natives.get_mut(index as usize) {
+            native.function.get_or_insert(function);
+        }
+        self.registers[first] = result.map_err(failed)?;
+        Ok(())
+    }
+    fn decode_opcode(&mut self) -> Result<Opcode, VmError> {
+        let byte = self.program[self.pc];
+        let opcode = Opcode::from(byte);
+        if opcode == Opcode::IGL {
+            return Err(VmError::IllegalOpcode { pc: self.pc, byte });
+        }
+        self.instruction_pc = self.pc;
+        self.instruction_opcode = opcode;
         self.pc += 1;
-        result
+        Ok(opcode)
     }
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        result
+    fn out_of_bounds(&self, address: i64) -> VmError {
+        VmError::PcOutOfBounds {
+            pc: self.instruction_pc,
+            opcode: self.instruction_opcode,
+            address,
+        }
+    }
+    fn next_8_bits(&mut self) -> Result<u8, VmError> {
+        match self.program.get(self.pc) {
+            Some(&byte) => {
+                self.pc += 1;
+                Ok(byte)
+            }
+            None => Err(self.out_of_bounds(self.pc as i64)),
+        }
+    }
+    fn next_bytes<const N: usize>(&mut self) -> Result<[u8; N], VmError> {
+        let mut bytes = [0; N];
+        for byte in bytes.iter_mut() {
+            *byte = self.next_8_bits()?;
+        }
+        Ok(bytes)
+    }
+    fn next_16_bits(&mut self) -> Result<i16, VmError> {
+        Ok(i16::from_be_bytes(self.next_bytes()?))
+    }
+    fn next_32_bits(&mut self) -> Result<i32, VmError> {
+        Ok(i32::from_be_bytes(self.next_bytes()?))
+    }
+    fn next_64_bits(&mut self) -> Result<i64, VmError> {
+        Ok(i64::from_be_bytes(self.next_bytes()?))
+    }
+    fn next_f64(&mut self) -> Result<f64, VmError> {
+        Ok(f64::from_bits(u64::from_be_bytes(self.next_bytes()?)))
+    }
+    fn next_address(&mut self) -> Result<u32, VmError> {
+        Ok(u32::from_be_bytes(self.next_bytes()?))
+    }
+    fn next_register(&mut self) -> Result<usize, VmError> {
+        let register = self.next_8_bits()?;
+        if (register as usize) < self.registers.len() {
+            Ok(register as usize)
+        } else {
+            Err(VmError::BadRegister {
+                pc: self.instruction_pc,
+                opcode: self.instruction_opcode,
+                register,
+            })
+        }
     }
-    fn next_32_bits(&mut self) -> u32 {
-        let result = ((self.program[self.pc] as u32) << 24)
-            | ((self.program[self.pc + 1] as u32) << 16)
-            | ((self.program[self.pc + 2] as u32) << 8)
-            | self.program[self.pc + 3] as u32;
-        self.pc += 4;
+    fn next_register_value(&mut self) -> Result<i32, VmError> {
+        let register = self.next_register()?;
+        Ok(self.registers[register])
+    }
+    fn next_float_register(&mut self) -> Result<usize, VmError> {
+        // Both banks have the same size, so the index check is the same.
+        self.next_register()
+    }
+    fn next_float_value(&mut self) -> Result<f64, VmError> {
+        let register = self.next_float_register()?;
+        Ok(self.float_registers[register])
+    }
+    fn next_long_register(&mut self) -> Result<usize, VmError> {
+        self.next_register()
+    }
+    fn next_long_value(&mut self) -> Result<i64, VmError> {
+        let register = self.next_long_register()?;
+        Ok(self.long_registers[register])
+    }
+    fn jump_to(&mut self, target: i64) -> Result<(), VmError> {
+        if target < 0 || target as usize > self.program.len() {
+            return Err(self.out_of_bounds(target));
+        }
+        self.pc = target as usize;
+        Ok(())
+    }
+    /// Allocates `bytes` on the heap and returns the address of the block.
+    fn allocate(&mut self, bytes: i32) -> Result<i32, VmError> {
+        usize::try_from(bytes)
+            .ok()
+            .and_then(|size| {
+                self.allocator
+                    .allocate(&mut self.heap, size, self.heap_limit)
+            })
+            .and_then(|offset| i32::try_from(HEAP_BASE as usize + offset).ok())
+            .ok_or(VmError::HeapOverflow {
+                pc: self.instruction_pc,
+                opcode: self.instruction_opcode,
+                requested: bytes as i64,
+            })
+    }
+    /// Sets the flags for an arithmetic result and applies the overflow mode.
+    fn arithmetic(&mut self, (result, overflow): (i32, bool), carry: bool) -> Result<i32, VmError> {
+        self.set_arithmetic_flags(Flags {
+            zero: result == 0,
+            negative: result < 0,
+            carry,
+            overflow,
+        })?;
+        Ok(result)
+    }
+    /// `arithmetic` for a 64-bit result.
+    fn arithmetic_long(
+        &mut self,
+        (result, overflow): (i64, bool),
+        carry: bool,
+    ) -> Result<i64, VmError> {
+        self.set_arithmetic_flags(Flags {
+            zero: result == 0,
+            negative: result < 0,
+            carry,
+            overflow,
+        })?;
+        Ok(result)
+    }
+    fn set_arithmetic_flags(&mut self, flags: Flags) -> Result<(), VmError> {
+        self.flags = flags;
+        if flags.overflow && self.overflow_mode == OverflowMode::Trapping {
+            return Err(VmError::ArithmeticOverflow {
+                pc: self.instruction_pc,
+                opcode: self.instruction_opcode,
+            });
+        }
+        Ok(())
+    }
+    /// Sets the flags for a bitwise result, carry and overflow are cleared.
+    fn logic(&mut self, result: i32) -> i32 {
+        self.flags = Flags {
+            zero: result == 0,
+            negative: result < 0,
+            carry: false,
+            overflow: false,
+        };
         result
     }
+    fn jump_if(&mut self, condition: bool) -> Result<(), VmError> {
+        let target = self.next_address()?;
+        if condition {
+            self.jump_to(target as i64)?;
+        }
+        Ok(())
+    }
+    /// Reads the `$base offset` operands of a load or store.
+    fn next_effective_address(&mut self) -> Result<i64, VmError> {
+        let base = self.next_register_value()?;
+        let offset = self.next_16_bits()?;
+        Ok(base as i64 + offset as i64)
+    }
+    fn memory_fault(&self, address: i64, write: bool) -> VmError {
+        VmError::MemoryFault {
+            pc: self.instruction_pc,
+            opcode: self.instruction_opcode,
+            address,
+            write,
+        }
+    }
+    fn read_memory<const N: usize>(&self, address: i64) -> Result<[u8; N], VmError> {
+        let (segment, start) = match address - HEAP_BASE as i64 {
+            start if start >= 0 => (&self.heap, start),
+            _ => (&self.ro_data, address),
+        };
+        let bytes = usize::try_from(start)
+            .ok()
+            .and_then(|start| segment.get(start..start.checked_add(N)?))
+            .ok_or_else(|| self.memory_fault(address, false))?;
+        let mut result = [0; N];
+        result.copy_from_slice(bytes);
+        Ok(result)
+    }
+    fn write_memory(&mut self, address: i64, bytes: &[u8]) -> Result<(), VmError> {
+        let fault = self.memory_fault(address, true);
+        let start = usize::try_from(address - HEAP_BASE as i64).map_err(|_| fault.clone())?;
+        let end = start
+            .checked_add(bytes.len())
+            .ok_or_else(|| fault.clone())?;
+        self.heap
+            .get_mut(start..end)
+            .ok_or(fault)?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
+    fn io_error(&self, error: io::Error) -> VmError {
+        VmError::Io {
+            pc: self.instruction_pc,
+            opcode: self.instruction_opcode,
+            kind: error.kind(),
+        }
+    }
+    fn print(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        self.output
+            .write_all(bytes)
+            .and_then(|_| self.output.flush())
+            .map_err(|error| self.io_error(error))
+    }
+    /// Reads `input` up to the next line break, which is dropped. `None`
+    /// once the input has ended.
+    fn read_line(&mut self) -> Result<Option<Vec<u8>>, VmError> {
+        let mut line = vec![];
+        let mut byte = [0];
+        loop {
+            match self.input.read(&mut byte) {
+                Ok(0) if line.is_empty() => return Ok(None),
+                Ok(0) => break,
+                Ok(_) if byte[0] == b'\n' => break,
+                Ok(_) => line.push(byte[0]),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(self.io_error(error)),
+            }
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+    fn syscall(&mut self) -> Result<Option<ExitStatus>, VmError> {
+        let number = self.registers[SYSCALL_REGISTER as usize];
+        let argument = self.registers[SYSCALL_REGISTER as usize + 1];
+        let service = Syscall::from_number(number).ok_or(VmError::UnknownSyscall {
+            pc: self.instruction_pc,
+            opcode: self.instruction_opcode,
+            number,
+        })?;
+        match service {
+            Syscall::Exit => return Ok(Some(ExitStatus::Exited(argument))),
+            Syscall::PrintInt => self.print(argument.to_string().as_bytes())?,
+            Syscall::PrintString => {
+                let mut text = vec![];
+                let mut address = argument as i64;
+                loop {
+                    match self.read_memory::<1>(address)? {
+                        [0] => break,
+                        [byte] => text.push(byte),
+                    }
+                    address += 1;
+                }
+                self.print(&text)?;
+            }
+            Syscall::ReadLine => match self.read_line()? {
+                Some(mut line) => {
+                    let length = line.len() as i32;
+                    line.push(0);
+                    let pointer = self.allocate(line.len() as i32)?;
+                    self.write_memory(pointer as i64, &line)?;
+                    self.registers[SYSCALL_REGISTER as usize] = pointer;
+                    self.registers[SYSCALL_REGISTER as usize + 1] = length;
+                }
+                None => self.registers[SYSCALL_REGISTER as usize + 1] = -1,
+            },
+        }
+        Ok(None)
+    }
+    fn push(&mut self, value: i32) -> Result<(), VmError> {
+        let sp = self.registers[SP as usize];
+        let end = sp as i64 + 4;
+        if sp < 0 || end > self.stack.len() as i64 {
+            return Err(VmError::StackOverflow {
+                pc: self.instruction_pc,
+                opcode: self.instruction_opcode,
+                sp,
+            });
+        }
+        self.stack[sp as usize..end as usize].copy_from_slice(&value.to_be_bytes());
+        self.registers[SP as usize] = end as i32;
+        Ok(())
+    }
+    fn pop(&mut self) -> Result<i32, VmError> {
+        let sp = self.registers[SP as usize];
+        if sp < 4 || sp as usize > self.stack.len() {
+            return Err(VmError::StackUnderflow {
+                pc: self.instruction_pc,
+                opcode: self.instruction_opcode,
+                sp,
+            });
+        }
+        let start = sp as usize - 4;
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.stack[start..sp as usize]);
+        self.registers[SP as usize] = start as i32;
+        Ok(i32::from_be_bytes(bytes))
+    }
+    /// Loads the code and read-only data of an assembled program and points
+    /// the program counter at its entry point.
+    pub fn load_program(&mut self, program: Program) {
+        self.program = program.code;
+        self.ro_data = program.ro_data;
+        self.symbols = program.symbols;
+        self.pc = program.entry as usize;
+    }
+    /// Validates an executable image written by `image::write_image` and
+    /// loads it, rejecting images with a wrong magic number or version.
+    pub fn load_image(&mut self, bytes: &[u8]) -> Result<(), ImageError> {
+        self.load_program(image::read_image(bytes)?);
+        Ok(())
+    }
     pub fn add_byte(&mut self, byte: u8) {
         self.program.push(byte);
     }
-    pub fn run_once_write_everywhere(&mut self) {
-        self.execute_instruction();
+    pub fn run(&mut self) -> Result<ExitStatus, VmError> {
+        loop {
+            match self.step() {
+                StepOutcome::Continue | StepOutcome::Yielded => {}
+                StepOutcome::Halted(status) => return Ok(status),
+                StepOutcome::Faulted(error) => return Err(error),
+            }
+        }
+    }
+    pub fn run_for(&mut self, max_instructions: usize) -> StepOutcome {
+        for _ in 0..max_instructions {
+            match self.step() {
+                StepOutcome::Continue => {}
+                outcome => return outcome,
+            }
+        }
+        StepOutcome::Yielded
     }
-    pub fn run(&mut self) {
-        let mut is_done = false;
-        while !is_done {
-            is_done = self.execute_instruction();
+    pub fn step(&mut self) -> StepOutcome {
+        match self.next_instruction() {
+            Ok(None) => StepOutcome::Continue,
+            Ok(Some(status)) => StepOutcome::Halted(status),
+            Err(error) => StepOutcome::Faulted(error),
         }
     }
-    fn execute_instruction(&mut self) -> bool {
+    fn next_instruction(&mut self) -> Result<Option<ExitStatus>, VmError> {
         if self.pc >= self.program.len() {
-            println!("***PROGRAM STOP***");
-            return true;
+            return Ok(Some(ExitStatus::EndOfProgram));
         }
-        match self.decode_opcode() {
+        let opcode = self.decode_opcode()?;
+        if let Some(mut observer) = self.observer.take() {
+            observer.before_instruction(self, self.instruction_pc, opcode);
+            self.observer = Some(observer);
+        }
+        let status = self.execute_instruction(opcode)?;
+        if let Some(mut observer) = self.observer.take() {
+            observer.after_instruction(self, self.instruction_pc, opcode);
+            self.observer = Some(observer);
+        }
+        Ok(status)
+    }
+    fn execute_instruction(&mut self, opcode: Opcode) -> Result<Option<ExitStatus>, VmError> {
+        match opcode {
+            Opcode::SYSCALL => return self.syscall(),
+            Opcode::CALLN => self.call_native()?,
+            Opcode::LOADL => {
+                let register = self.next_long_register()?;
+                self.long_registers[register] = self.next_64_bits()?;
+            }
+            Opcode::ADDL => {
+                let register1 = self.next_long_value()?;
+                let register2 = self.next_long_value()?;
+                let destination = self.next_long_register()?;
+                let carry = (register1 as u64).overflowing_add(register2 as u64).1;
+                let result = self.arithmetic_long(register1.overflowing_add(register2), carry)?;
+                self.long_registers[destination] = result;
+            }
+            Opcode::SUBL => {
+                let register1 = self.next_long_value()?;
+                let register2 = self.next_long_value()?;
+                let destination = self.next_long_register()?;
+                let borrow = (register1 as u64) < (register2 as u64);
+                let result = self.arithmetic_long(register1.overflowing_sub(register2), borrow)?;
+                self.long_registers[destination] = result;
+            }
+            Opcode::MULL => {
+                let register1 = self.next_long_value()?;
+                let register2 = self.next_long_value()?;
+                let destination = self.next_long_register()?;
+                let product = register1.overflowing_mul(register2);
+                let result = self.arithmetic_long(product, product.1)?;
+                self.long_registers[destination] = result;
+            }
+            // Unlike `DIV`, the remainder is not kept, it would not fit in
+            // `remainder`.
+            Opcode::DIVL => {
+                let register1 = self.next_long_value()?;
+                let register2 = self.next_long_value()?;
+                let destination = self.next_long_register()?;
+                if register2 == 0 {
+                    return Err(VmError::DivideByZero {
+                        pc: self.instruction_pc,
+                        opcode: Opcode::DIVL,
+                    });
+                }
+                let result = self.arithmetic_long(register1.overflowing_div(register2), false)?;
+                self.long_registers[destination] = result;
+            }
+            Opcode::EQL => {
+                let register1 = self.next_long_value()?;
+                let register2 = self.next_long_value()?;
+                self.equal_flag = register1 == register2;
+            }
+            Opcode::LTL => {
+                let register1 = self.next_long_value()?;
+                let register2 = self.next_long_value()?;
+                self.equal_flag = register1 < register2;
+            }
+            Opcode::ITOL => {
+                let value = self.next_register_value()?;
+                self.long_registers[self.next_long_register()?] = value as i64;
+            }
+            // Keeps the low 32 bits, like a wrapping `as` cast.
+            Opcode::LTOI => {
+                let value = self.next_long_value()?;
+                self.registers[self.next_register()?] = value as i32;
+            }
+            Opcode::LOADF => {
+                let register = self.next_float_register()?;
+                self.float_registers[register] = self.next_f64()?;
+            }
+            // Float arithmetic follows IEEE 754, dividing by zero gives an
+            // infinity or NaN instead of a fault.
+            Opcode::ADDF => {
+                let register1 = self.next_float_value()?;
+                let register2 = self.next_float_value()?;
+                self.float_registers[self.next_float_register()?] = register1 + register2;
+            }
+            Opcode::SUBF => {
+                let register1 = self.next_float_value()?;
+                let register2 = self.next_float_value()?;
+                self.float_registers[self.next_float_register()?] = register1 - register2;
+            }
+            Opcode::MULF => {
+                let register1 = self.next_float_value()?;
+                let register2 = self.next_float_value()?;
+                self.float_registers[self.next_float_register()?] = register1 * register2;
+            }
+            Opcode::DIVF => {
+                let register1 = self.next_float_value()?;
+                let register2 = self.next_float_value()?;
+                self.float_registers[self.next_float_register()?] = register1 / register2;
+            }
+            Opcode::EQF => {
+                let register1 = self.next_float_value()?;
+                let register2 = self.next_float_value()?;
+                self.equal_flag = register1 == register2;
+            }
+            Opcode::LTF => {
+                let register1 = self.next_float_value()?;
+                let register2 = self.next_float_value()?;
+                self.equal_flag = register1 < register2;
+            }
+            Opcode::ITOF => {
+                let value = self.next_register_value()?;
+                self.float_registers[self.next_float_register()?] = value as f64;
+            }
+            // Truncates towards zero and saturates, NaN becomes 0.
+            Opcode::FTOI => {
+                let value = self.next_float_value()?;
+                self.registers[self.next_register()?] = value as i32;
+            }
+            Opcode::JZ => self.jump_if(self.flags.zero)?,
+            Opcode::JNZ => self.jump_if(!self.flags.zero)?,
+            Opcode::JN => self.jump_if(self.flags.negative)?,
+            Opcode::JO => self.jump_if(self.flags.overflow)?,
+            Opcode::AND => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                self.registers[destination] = self.logic(register1 & register2);
+            }
+            Opcode::OR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                self.registers[destination] = self.logic(register1 | register2);
+            }
+            Opcode::XOR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                self.registers[destination] = self.logic(register1 ^ register2);
+            }
+            Opcode::NOT => {
+                let register1 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                self.registers[destination] = self.logic(!register1);
+            }
+            // Shift amounts are taken modulo 32, like on most hardware.
+            Opcode::SHL => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                let result = self.logic(register1.wrapping_shl(register2 as u32));
+                self.registers[destination] = result;
+            }
+            Opcode::SHR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                let result = self.logic((register1 as u32).wrapping_shr(register2 as u32) as i32);
+                self.registers[destination] = result;
+            }
+            Opcode::SAR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                let result = self.logic(register1.wrapping_shr(register2 as u32));
+                self.registers[destination] = result;
+            }
+            Opcode::MOD => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                if register2 == 0 {
+                    return Err(VmError::DivideByZero {
+                        pc: self.instruction_pc,
+                        opcode: Opcode::MOD,
+                    });
+                }
+                // `i32::MIN % -1` is 0 exactly, only the division overflows.
+                let result = self.arithmetic((register1.wrapping_rem(register2), false), false)?;
+                self.registers[destination] = result;
+            }
+            Opcode::INC => {
+                let register = self.next_register()?;
+                let value = self.registers[register];
+                self.registers[register] =
+                    self.arithmetic(value.overflowing_add(1), value == -1)?;
+            }
+            Opcode::DEC => {
+                let register = self.next_register()?;
+                let value = self.registers[register];
+                self.registers[register] = self.arithmetic(value.overflowing_sub(1), value == 0)?;
+            }
+            Opcode::LOADB => {
+                let register = self.next_register()?;
+                let address = self.next_effective_address()?;
+                let [byte] = self.read_memory(address)?;
+                self.registers[register] = byte as i32;
+            }
+            Opcode::LOADW => {
+                let register = self.next_register()?;
+                let address = self.next_effective_address()?;
+                self.registers[register] = i32::from_be_bytes(self.read_memory(address)?);
+            }
+            Opcode::STOREB => {
+                let value = self.next_register_value()?;
+                let address = self.next_effective_address()?;
+                self.write_memory(address, &[value as u8])?;
+            }
+            Opcode::STOREW => {
+                let value = self.next_register_value()?;
+                let address = self.next_effective_address()?;
+                self.write_memory(address, &value.to_be_bytes())?;
+            }
+            Opcode::FREE => {
+                let pointer = self.next_register_value()?;
+                let freed = usize::try_from(pointer as i64 - HEAP_BASE as i64)
+                    .is_ok_and(|offset| self.allocator.free(&mut self.heap, offset));
+                if !freed {
+                    return Err(VmError::InvalidFree {
+                        pc: self.instruction_pc,
+                        opcode: Opcode::FREE,
+                        pointer: pointer as i64,
+                    });
+                }
+            }
+            Opcode::PUSH => {
+                let value = self.next_register_value()?;
+                self.push(value)?;
+            }
+            Opcode::POP => {
+                let register = self.next_register()?;
+                self.registers[register] = self.pop()?;
+            }
+            Opcode::CALL => {
+                let target = self.next_address()?;
+                self.push(self.pc as i32)?;
+                self.jump_to(target as i64)?;
+            }
+            Opcode::RET => {
+                let target = self.pop()?;
+                self.jump_to(target as i64)?;
+            }
             Opcode::SET => {
-                let register = self.next_8_bits() as usize;
-
-                let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
-                self.heap.resize(new_end as usize, 0);
+                // Grows the heap by free space a later ALLOC can take.
+                let bytes = self.next_register_value()?;
+                let reserved = usize::try_from(bytes).is_ok_and(|size| {
+                    self.allocator
+                        .reserve(&mut self.heap, size, self.heap_limit)
+                });
+                if !reserved {
+                    return Err(VmError::HeapOverflow {
+                        pc: self.instruction_pc,
+                        opcode: self.instruction_opcode,
+                        requested: bytes as i64,
+                    });
+                }
             }
             Opcode::ALLOC => {
-                let register = self.next_8_bits() as usize;
-                println!("test");
-
-                let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
-                println!("bytes {:?}", bytes);
-
-                for i in 0..bytes {
-                    println!("{:?}", bytes);
-                }
-                self.heap.resize(new_end as usize, 0);
+                let bytes = self.next_register_value()?;
+                let pointer = self.allocate(bytes)?;
+                self.registers[self.next_register()?] = pointer;
             }
             Opcode::SQUARE => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register1;
-                println!("RESULT: {:?}", register1 * register1,);
-
-                return true;
+                let register1 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                let product = register1.overflowing_mul(register1);
+                let result = self.arithmetic(product, product.1)?;
+                self.registers[destination] = result;
+            }
+            Opcode::JMPA => {
+                let target = self.next_address()?;
+                self.jump_to(target as i64)?;
             }
-            Opcode::LABEL => {
-                let register = self.next_8_bits() as usize;
-                let target = self.registers[register];
+            Opcode::JMPEQA => {
+                let target = self.next_address()?;
                 if self.equal_flag {
-                    self.pc = target as usize;
+                    self.jump_to(target as i64)?;
                 }
             }
             Opcode::JMPEQ => {
-                let register = self.next_8_bits() as usize;
-                let target = self.registers[register];
+                let target = self.next_register_value()?;
                 if self.equal_flag {
-                    self.pc = target as usize;
+                    self.jump_to(target as i64)?;
                 }
             }
-            Opcode::LTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 <= register2 {
-                    self.equal_flag = true
-                } else {
-                    self.equal_flag = false
+            Opcode::JMPNEQA => self.jump_if(!self.equal_flag)?,
+            Opcode::JMPNEQ => {
+                let target = self.next_register_value()?;
+                if !self.equal_flag {
+                    self.jump_to(target as i64)?;
                 }
-                self.next_8_bits();
+            }
+            Opcode::LTQ => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 <= register2;
             }
             Opcode::GTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 >= register2 {
-                    self.equal_flag = true
-                } else {
-                    self.equal_flag = false
-                }
-                self.next_8_bits();
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 >= register2;
             }
             Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 < register2 {
-                    self.equal_flag = true
-                } else {
-                    self.equal_flag = false
-                }
-                self.next_8_bits();
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 < register2;
             }
             Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 > register2 {
-                    self.equal_flag = true
-                } else {
-                    self.equal_flag = false
-                }
-                self.next_8_bits();
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 > register2;
             }
             Opcode::NEQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 != register2 {
-                    self.equal_flag = true
-                } else {
-                    self.equal_flag = false
-                }
-                self.next_8_bits();
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 != register2;
             }
             Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 == register2 {
-                    self.equal_flag = true
-                } else {
-                    self.equal_flag = false
-                }
-                self.next_8_bits();
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.equal_flag = register1 == register2;
             }
+            // Relative jumps count from the end of the instruction.
             Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc += value as usize;
-                println!("JMP value: {:?}", value);
+                let value = self.next_register_value()?;
+                self.jump_to(self.pc as i64 + value as i64)?;
+            }
+            Opcode::JMPB => {
+                let value = self.next_register_value()?;
+                self.jump_to(self.pc as i64 - value as i64)?;
             }
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
-                println!("JMP target: {:?}", target)
+                let target = self.next_register_value()?;
+                self.jump_to(target as i64)?;
             }
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as u32;
-                println!("DIV r1: {} r2: {}", register1, register2);
-                println!(
-                    "RESULT: {:?} REST: {:?}",
-                    register1 / register2,
-                    self.remainder
-                );
-
-                return true;
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                if register2 == 0 {
+                    return Err(VmError::DivideByZero {
+                        pc: self.instruction_pc,
+                        opcode: Opcode::DIV,
+                    });
+                }
+                let result = self.arithmetic(register1.overflowing_div(register2), false)?;
+                self.registers[destination] = result;
+                self.remainder = register1.wrapping_rem(register2) as u32;
             }
             Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                println!("MUL r1: {} r2: {}", register1, register2,);
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
-                println!("RESULT: {:?}", register1 * register2);
-
-                return true;
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                let product = register1.overflowing_mul(register2);
+                let result = self.arithmetic(product, product.1)?;
+                self.registers[destination] = result;
             }
             Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                println!("SUB r1: {} r2: {}", register1, register2,);
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
-                println!("RESULT: {:?}", register1 - register2);
-
-                return true;
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                let borrow = (register1 as u32) < (register2 as u32);
+                let result = self.arithmetic(register1.overflowing_sub(register2), borrow)?;
+                self.registers[destination] = result;
             }
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                println!("ADD r1: {} r2: {}", register1, register2,);
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
-                println!("RESULT: {:?}", register1 + register2);
-
-                return true;
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let destination = self.next_register()?;
+                let carry = (register1 as u32).overflowing_add(register2 as u32).1;
+                let result = self.arithmetic(register1.overflowing_add(register2), carry)?;
+                self.registers[destination] = result;
             }
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_32_bits();
-                println!("{:?}", number);
-                println!("LOAD value:{:?} to r{:?}", number, register);
-
-                self.registers[register] = number as i32;
-                return false;
+                let register = self.next_register()?;
+                self.registers[register] = self.next_32_bits()?;
             }
-            Opcode::HLT => {
-                println!("***PROGRAM STOP***");
-                return true;
+            Opcode::LOADS => {
+                let register = self.next_register()?;
+                self.registers[register] = self.next_16_bits()? as i32;
             }
-            _ => {
-                println!(
-                    "***ERROR***\nUnrecognized opcode {:?}",
-                    self.decode_opcode()
-                );
+            Opcode::HLT => {
+                return Ok(Some(ExitStatus::Halted));
             }
+            Opcode::IGL => unreachable!("decode_opcode rejects IGL"),
         }
-        true
+        Ok(None)
     }
 }
 
@@ -243,6 +1215,12 @@ impl VM {
 mod tests {
     use super::*;
 
+    // Fails to compile if a field makes the VM `!Send`.
+    fn _assert_send<T: Send>() {}
+    fn _vm_is_send() {
+        _assert_send::<VM>();
+    }
+
     #[test]
     fn test_create_vm() {
         let test_vm = VM::new();
@@ -252,37 +1230,38 @@ mod tests {
     #[test]
     fn test_opcode_hlt() {
         let mut test_vm = VM::new();
-        let test_bytes = vec![2, 0, 0, 0];
+        let test_bytes = vec![0, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
         assert_eq!(test_vm.pc, 1);
     }
     #[test]
 
     fn test_unrecognized() {
         let mut test_vm = VM::new();
-        let test_bytes = vec![5, 0, 0, 0];
+        let test_bytes = vec![200, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
-        assert_eq!(test_vm.pc, 1);
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::IllegalOpcode { pc: 0, byte: 200 })
+        );
+        assert_eq!(test_vm.pc, 0);
     }
     #[test]
     fn test_load_opcode() {
         let mut test_vm = VM::new();
-        let test_bytes = vec![1, 0, 4, 0, 1, 0, 1, 0];
+        let test_bytes = vec![1, 0, 0, 0, 4, 1];
         test_vm.program = test_bytes;
-        test_vm.run();
-        println!("{:?}", test_vm.registers[0]);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
         assert_eq!(test_vm.registers[0], 1025);
     }
     #[test]
 
     fn test_alu() {
         let mut test_vm = VM::new();
-        let test_bytes = vec![1, 0, 0, 100, 1, 1, 1, 30, 5, 0, 1, 0];
+        let test_bytes = vec![1, 0, 0, 0, 8, 0, 1, 1, 0, 0, 0, 2, 5, 0, 1, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
-        println!("{:?}", test_vm.registers);
+        test_vm.run().unwrap();
 
         assert_eq!(test_vm.registers[0], 1024);
     }
@@ -293,7 +1272,7 @@ mod tests {
         let test_bytes = vec![6, 0, 0, 0];
         test_vm.registers[0] = 1;
         test_vm.program = test_bytes;
-        test_vm.run_once_write_everywhere();
+        assert_eq!(test_vm.step(), StepOutcome::Continue);
 
         assert_eq!(test_vm.pc, 1);
     }
@@ -305,8 +1284,957 @@ mod tests {
         test_vm.registers[0] = 2;
         test_vm.equal_flag = true;
         test_vm.program = test_bytes;
-        test_vm.run_once_write_everywhere();
+        assert_eq!(test_vm.step(), StepOutcome::Continue);
+
+        assert_eq!(test_vm.pc, 2);
+    }
+    #[test]
+    fn test_jmpneq() {
+        // JMPNEQ $0, JMPNEQA 0
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.program = vec![63, 0, 64, 0, 0, 0, 0, 0];
+        test_vm.equal_flag = true;
+        test_vm.step();
+        assert_eq!(test_vm.pc, 2);
+        test_vm.step();
+        assert_eq!(test_vm.pc, 7);
+
+        test_vm.equal_flag = false;
+        test_vm.pc = 0;
+        test_vm.step();
+        assert_eq!(test_vm.pc, 7);
+        test_vm.pc = 2;
+        test_vm.step();
+        assert_eq!(test_vm.pc, 0);
+    }
+    #[test]
+    fn test_relative_jumps() {
+        // JMPF $1 over the HLT, then JMPB $2 back to it
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = 3;
+        test_vm.program = vec![7, 1, 0, 65, 2];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.pc, 3);
+
+        test_vm.registers[2] = 6;
+        test_vm.pc = 3;
+        assert!(matches!(
+            test_vm.run(),
+            Err(VmError::PcOutOfBounds { address: -1, .. })
+        ));
+    }
+    #[test]
+    fn test_label_is_not_an_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![15, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::IllegalOpcode { pc: 0, byte: 15 })
+        );
+    }
+    #[test]
+    fn test_divide_by_zero() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.program = vec![5, 0, 1, 2];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::DivideByZero {
+                pc: 0,
+                opcode: Opcode::DIV
+            })
+        );
+    }
+    #[test]
+    fn test_bad_register() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 2, 1, 40, 3];
+        test_vm.pc = 1;
+        let error = test_vm.run().unwrap_err();
+        assert_eq!(
+            error,
+            VmError::BadRegister {
+                pc: 1,
+                opcode: Opcode::ADD,
+                register: 40
+            }
+        );
+        assert_eq!(error.pc(), 1);
+        assert_eq!(error.opcode(), Some(Opcode::ADD));
+    }
+    #[test]
+    fn test_bad_destination_keeps_flags() {
+        let programs = [
+            vec![2, 1, 2, 40],  // add $1 $2 $40
+            vec![31, 1, 2, 40], // and $1 $2 $40
+            vec![16, 1, 40],    // square $1 $40
+            vec![55, 1, 2, 40], // addl $l1 $l2 $l40
+        ];
+        for program in programs {
+            let mut test_vm = VM::new();
+            test_vm.flags = flags(false, true, true, true);
+            test_vm.program = program;
+            assert!(matches!(
+                test_vm.run(),
+                Err(VmError::BadRegister { register: 40, .. })
+            ));
+            assert_eq!(test_vm.flags, flags(false, true, true, true));
+        }
+    }
+    #[test]
+    fn test_truncated_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 0, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::PcOutOfBounds {
+                pc: 0,
+                opcode: Opcode::LOAD,
+                address: 4
+            })
+        );
+    }
+    #[test]
+    fn test_jump_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 100;
+        test_vm.program = vec![6, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::PcOutOfBounds {
+                pc: 0,
+                opcode: Opcode::JMP,
+                address: 100
+            })
+        );
+    }
+    #[test]
+    fn test_heap_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.heap_limit = 16;
+        test_vm.registers[0] = 8;
+        test_vm.registers[1] = 9;
+        test_vm.program = vec![17, 0, 2, 17, 1, 3];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::HeapOverflow {
+                pc: 3,
+                opcode: Opcode::ALLOC,
+                requested: 9
+            })
+        );
+        assert_eq!(test_vm.heap.len(), 8);
+    }
+    #[test]
+    fn test_run_continues_past_alu() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 3;
+        test_vm.registers[2] = 4;
+        // ADD $1 $2 $3, MUL $3 $3 $4, SUB $4 $1 $5, HLT
+        test_vm.program = vec![2, 1, 2, 3, 4, 3, 3, 4, 3, 4, 1, 5, 0];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[3], 7);
+        assert_eq!(test_vm.registers[4], 49);
+        assert_eq!(test_vm.registers[5], 46);
+        assert_eq!(test_vm.pc, 13);
+    }
+    #[test]
+    fn test_step_outcomes() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![2, 1, 2, 3, 0];
+        assert_eq!(test_vm.step(), StepOutcome::Continue);
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.step(), StepOutcome::Halted(ExitStatus::Halted));
 
+        test_vm.program = vec![5, 0, 1, 2];
+        test_vm.pc = 0;
+        assert_eq!(
+            test_vm.step(),
+            StepOutcome::Faulted(VmError::DivideByZero {
+                pc: 0,
+                opcode: Opcode::DIV
+            })
+        );
+    }
+    #[test]
+    fn test_run_for_yields() {
+        let mut test_vm = VM::new();
+        // ADD $0 $0 $0 three times
+        test_vm.program = vec![2, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0];
+        assert_eq!(test_vm.run_for(2), StepOutcome::Yielded);
+        assert_eq!(test_vm.pc, 8);
+        assert_eq!(
+            test_vm.run_for(2),
+            StepOutcome::Halted(ExitStatus::EndOfProgram)
+        );
+    }
+    #[test]
+    fn test_compare_has_no_padding() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 5;
+        test_vm.registers[2] = 5;
+        // EQ $1 $2, GT $1 $2
+        test_vm.program = vec![8, 1, 2, 10, 1, 2];
+        assert_eq!(test_vm.step(), StepOutcome::Continue);
+        assert!(test_vm.equal_flag);
+        assert_eq!(test_vm.pc, 3);
+        assert_eq!(test_vm.step(), StepOutcome::Continue);
+        assert!(!test_vm.equal_flag);
+        assert_eq!(test_vm.pc, 6);
+    }
+    #[test]
+    fn test_load_negative_immediates() {
+        let mut test_vm = VM::new();
+        // LOAD $0 -2, LOADS $1 -300
+        test_vm.program = vec![1, 0, 255, 255, 255, 254, 19, 1, 254, 212];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], -2);
+        assert_eq!(test_vm.registers[1], -300);
+    }
+    #[test]
+    fn test_jump_to_address() {
+        let mut test_vm = VM::new();
+        // JMPA 7, HLT, HLT, JMPEQA 0, HLT
+        test_vm.program = vec![20, 0, 0, 0, 7, 0, 0, 21, 0, 0, 0, 0, 0];
+        assert_eq!(test_vm.step(), StepOutcome::Continue);
         assert_eq!(test_vm.pc, 7);
+        assert_eq!(test_vm.step(), StepOutcome::Continue);
+        assert_eq!(test_vm.pc, 12);
+        test_vm.equal_flag = true;
+        test_vm.pc = 7;
+        assert_eq!(test_vm.step(), StepOutcome::Continue);
+        assert_eq!(test_vm.pc, 0);
+    }
+    #[test]
+    fn test_load_image() {
+        let program = Program {
+            entry: 6,
+            code: vec![1, 1, 0, 0, 0, 7, 1, 2, 0, 0, 0, 9, 0],
+            ro_data: vec![1, 2, 3],
+            ..Program::default()
+        };
+        let mut test_vm = VM::new();
+        test_vm
+            .load_image(&image::write_image(&program).unwrap())
+            .unwrap();
+        assert_eq!(test_vm.ro_data, vec![1, 2, 3]);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[1], 0);
+        assert_eq!(test_vm.registers[2], 9);
+
+        let mut bad_image = image::write_image(&program).unwrap();
+        bad_image[0] = b'X';
+        assert!(matches!(
+            test_vm.load_image(&bad_image),
+            Err(ImageError::BadMagic { .. })
+        ));
+    }
+    #[test]
+    fn test_push_pop() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = -5;
+        test_vm.registers[2] = 7;
+        test_vm.program = vec![22, 1, 22, 2, 23, 3, 23, 4];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(test_vm.registers[3], 7);
+        assert_eq!(test_vm.registers[4], -5);
+        assert_eq!(test_vm.registers[SP as usize], 0);
+        assert_eq!(&test_vm.stack[0..8], &[255, 255, 255, 251, 0, 0, 0, 7]);
+    }
+    #[test]
+    fn test_call_ret() {
+        let mut test_vm = VM::new();
+        // call 7; halt; padding; sub: load $1 42; ret
+        test_vm.program = vec![24, 0, 0, 0, 7, 0, 0, 19, 1, 0, 42, 25];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.pc, 6);
+        assert_eq!(test_vm.registers[1], 42);
+        assert_eq!(test_vm.registers[SP as usize], 0);
+    }
+    #[test]
+    fn test_stack_faults() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![23, 1];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::StackUnderflow {
+                pc: 0,
+                opcode: Opcode::POP,
+                sp: 0
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.stack = vec![0; 8];
+        test_vm.program = vec![22, 1, 22, 1, 22, 1];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::StackOverflow {
+                pc: 4,
+                opcode: Opcode::PUSH,
+                sp: 8
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![25];
+        assert_eq!(test_vm.run().unwrap_err().opcode(), Some(Opcode::RET));
+    }
+    #[test]
+    fn test_load_store_heap() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 8;
+        test_vm.registers[2] = -2;
+        test_vm.registers[3] = 0x1ff;
+        test_vm.program = vec![
+            17, 0, 1, // alloc $0 $1
+            29, 2, 1, 0, 4, // storew $2 $1 4
+            28, 3, 1, 0, 0, // storeb $3 $1 0
+            27, 4, 1, 0, 4, // loadw $4 $1 4
+            26, 5, 1, 0, 0, // loadb $5 $1 0
+            26, 6, 1, 0, 7, // loadb $6 $1 7
+        ];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(test_vm.heap, vec![255, 0, 0, 0, 255, 255, 255, 254]);
+        assert_eq!(test_vm.registers[1], HEAP_BASE as i32);
+        assert_eq!(test_vm.registers[4], -2);
+        assert_eq!(test_vm.registers[5], 255);
+        assert_eq!(test_vm.registers[6], 254);
+    }
+    #[test]
+    fn test_load_ro_data() {
+        let mut test_vm = VM::new();
+        test_vm.ro_data = vec![b'h', b'i', 0, 0, 0, 1, 0];
+        test_vm.registers[1] = 3;
+        test_vm.program = vec![26, 2, 0, 0, 1, 27, 3, 1, 255, 255];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(test_vm.registers[2], b'i' as i32);
+        assert_eq!(test_vm.registers[3], 1);
+    }
+    #[test]
+    fn test_memory_faults() {
+        let mut test_vm = VM::new();
+        test_vm.ro_data = vec![1, 2, 3];
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = HEAP_BASE as i32;
+        let faults = [
+            (vec![27, 2, 0, 0, 0], 0, false), // loadw past the ro data
+            (vec![28, 2, 0, 0, 1], 1, true),  // storeb into the ro data
+            (vec![27, 2, 1, 0, 1], HEAP_BASE as i64 + 1, false), // loadw past the heap
+            (vec![29, 2, 1, 255, 255], HEAP_BASE as i64 - 1, true), // storew below the heap
+            (vec![26, 2, 0, 255, 255], -1, false), // loadb below zero
+        ];
+        for (program, address, write) in faults {
+            test_vm.program = program;
+            test_vm.pc = 0;
+            assert_eq!(
+                test_vm.run(),
+                Err(VmError::MemoryFault {
+                    pc: 0,
+                    opcode: test_vm.instruction_opcode,
+                    address,
+                    write
+                })
+            );
+        }
+    }
+    #[test]
+    fn test_alloc_free() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 6;
+        test_vm.registers[1] = 4;
+        test_vm.program = vec![
+            17, 0, 2, // alloc $0 $2
+            17, 1, 3, // alloc $1 $3
+            30, 2, // free $2
+            17, 1, 4, // alloc $1 $4
+            30, 4, // free $4
+            30, 3, // free $3
+            30, 3, // free $3
+        ];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::InvalidFree {
+                pc: 15,
+                opcode: Opcode::FREE,
+                pointer: HEAP_BASE as i64 + 8
+            })
+        );
+        assert_eq!(test_vm.registers[2], HEAP_BASE as i32);
+        assert_eq!(test_vm.registers[3], HEAP_BASE as i32 + 8);
+        assert_eq!(test_vm.registers[4], HEAP_BASE as i32);
+        assert!(test_vm.heap.is_empty());
+        assert!(test_vm.allocator.blocks().is_empty());
+
+        test_vm.registers[0] = -1;
+        test_vm.program = vec![17, 0, 1, 30, 0];
+        test_vm.pc = 0;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::HeapOverflow {
+                pc: 0,
+                opcode: Opcode::ALLOC,
+                requested: -1
+            })
+        );
+        test_vm.pc = 3;
+        assert_eq!(test_vm.run().unwrap_err().pc(), 3);
+    }
+    #[test]
+    fn test_set_reserves_free_space() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 8;
+        test_vm.program = vec![
+            18, 0, // set $0
+            17, 0, 1, // alloc $0 $1
+        ];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(test_vm.registers[1], HEAP_BASE as i32);
+        assert_eq!(test_vm.heap.len(), 8);
+        assert_eq!(test_vm.allocator.stats().live_blocks, 1);
+        assert_eq!(test_vm.allocator.stats().free_blocks, 0);
+
+        test_vm.registers[0] = -1;
+        test_vm.pc = 0;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::HeapOverflow {
+                pc: 0,
+                opcode: Opcode::SET,
+                requested: -1
+            })
+        );
+    }
+    #[test]
+    fn test_bitwise() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 0b1100;
+        test_vm.registers[2] = 0b1010;
+        test_vm.program = vec![
+            31, 1, 2, 3, // and $1 $2 $3
+            32, 1, 2, 4, // or $1 $2 $4
+            33, 1, 2, 5, // xor $1 $2 $5
+            34, 1, 6, // not $1 $6
+        ];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(test_vm.registers[3], 0b1000);
+        assert_eq!(test_vm.registers[4], 0b1110);
+        assert_eq!(test_vm.registers[5], 0b0110);
+        assert_eq!(test_vm.registers[6], !0b1100);
+    }
+    #[test]
+    fn test_shifts() {
+        let cases = [
+            (35, 1, 4, 16),
+            (35, 1, 31, i32::MIN),
+            (35, 1, 33, 2),
+            (36, -16, 2, 0x3fff_fffc),
+            (36, -1, 31, 1),
+            (37, -16, 2, -4),
+            (37, i32::MIN, 31, -1),
+            (37, 64, 3, 8),
+        ];
+        for (opcode, value, amount, expected) in cases {
+            let mut test_vm = VM::new();
+            test_vm.registers[1] = value;
+            test_vm.registers[2] = amount;
+            test_vm.program = vec![opcode, 1, 2, 3];
+            test_vm.run().unwrap();
+            assert_eq!(test_vm.registers[3], expected, "{:?}", Opcode::from(opcode));
+        }
+    }
+    #[test]
+    fn test_mod_inc_dec() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = -7;
+        test_vm.registers[2] = 3;
+        test_vm.registers[4] = i32::MAX;
+        test_vm.registers[5] = i32::MIN;
+        test_vm.program = vec![38, 1, 2, 3, 39, 4, 40, 5, 39, 2, 40, 1];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(test_vm.registers[3], -1);
+        assert_eq!(test_vm.registers[4], i32::MIN);
+        assert_eq!(test_vm.registers[5], i32::MAX);
+        assert_eq!(test_vm.registers[2], 4);
+        assert_eq!(test_vm.registers[1], -8);
+
+        test_vm.program = vec![38, 1, 0, 3];
+        test_vm.pc = 0;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::DivideByZero {
+                pc: 0,
+                opcode: Opcode::MOD
+            })
+        );
+    }
+    fn flags_after(opcode: Opcode, register1: i32, register2: i32) -> (i32, Flags) {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = register1;
+        test_vm.registers[2] = register2;
+        test_vm.program = vec![opcode.into(), 1, 2, 3];
+        test_vm.run().unwrap();
+        (test_vm.registers[3], test_vm.flags)
+    }
+    fn flags(zero: bool, negative: bool, carry: bool, overflow: bool) -> Flags {
+        Flags {
+            zero,
+            negative,
+            carry,
+            overflow,
+        }
+    }
+    #[test]
+    fn test_arithmetic_flags() {
+        let cases = [
+            (Opcode::ADD, 2, 3, 5, flags(false, false, false, false)),
+            (Opcode::ADD, -1, 1, 0, flags(true, false, true, false)),
+            (
+                Opcode::ADD,
+                i32::MAX,
+                1,
+                i32::MIN,
+                flags(false, true, false, true),
+            ),
+            (
+                Opcode::ADD,
+                i32::MIN,
+                -1,
+                i32::MAX,
+                flags(false, false, true, true),
+            ),
+            (Opcode::SUB, 1, 2, -1, flags(false, true, true, false)),
+            (
+                Opcode::SUB,
+                i32::MIN,
+                1,
+                i32::MAX,
+                flags(false, false, false, true),
+            ),
+            (Opcode::SUB, 5, 5, 0, flags(true, false, false, false)),
+            (
+                Opcode::MUL,
+                1 << 16,
+                1 << 16,
+                0,
+                flags(true, false, true, true),
+            ),
+            (Opcode::MUL, -3, 4, -12, flags(false, true, false, false)),
+            (
+                Opcode::DIV,
+                i32::MIN,
+                -1,
+                i32::MIN,
+                flags(false, true, false, true),
+            ),
+            (
+                Opcode::MOD,
+                i32::MIN,
+                -1,
+                0,
+                flags(true, false, false, false),
+            ),
+            (Opcode::XOR, 6, 6, 0, flags(true, false, false, false)),
+        ];
+        for (opcode, register1, register2, result, expected) in cases {
+            assert_eq!(
+                flags_after(opcode, register1, register2),
+                (result, expected),
+                "{:?} {} {}",
+                opcode,
+                register1,
+                register2
+            );
+        }
+        let squares = [
+            (-3, 9, flags(false, false, false, false)),
+            (1 << 16, 0, flags(true, false, true, true)),
+            (50_000, -1_794_967_296, flags(false, true, true, true)),
+        ];
+        for (value, result, expected) in squares {
+            let mut test_vm = VM::new();
+            test_vm.registers[1] = value;
+            test_vm.program = vec![16, 1, 2];
+            test_vm.run().unwrap();
+            assert_eq!((test_vm.registers[2], test_vm.flags), (result, expected));
+        }
+    }
+    #[test]
+    fn test_trapping_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.overflow_mode = OverflowMode::Trapping;
+        test_vm.registers[1] = i32::MAX;
+        test_vm.registers[2] = 1;
+        test_vm.program = vec![2, 2, 2, 3, 2, 1, 2, 3];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::ArithmeticOverflow {
+                pc: 4,
+                opcode: Opcode::ADD
+            })
+        );
+        assert_eq!(test_vm.registers[3], 2);
+        assert!(test_vm.flags.overflow);
+
+        test_vm.registers[4] = i32::MIN;
+        test_vm.program = vec![40, 4];
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run().unwrap_err().opcode(), Some(Opcode::DEC));
+        assert_eq!(test_vm.registers[4], i32::MIN);
+
+        test_vm.registers[5] = -1;
+        test_vm.program = vec![38, 4, 5, 6];
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(test_vm.registers[6], 0);
+    }
+    #[test]
+    fn test_flag_jumps() {
+        // dec $1; jz 11; jmp 0 (through $0); halt at 11
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 3;
+        test_vm.program = vec![40, 1, 41, 0, 0, 0, 11, 6, 0, 0, 0, 0];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[1], 0);
+        assert_eq!(test_vm.pc, 12);
+
+        let jumps = [
+            (Opcode::JNZ, flags(false, false, false, false), true),
+            (Opcode::JNZ, flags(true, false, false, false), false),
+            (Opcode::JN, flags(false, true, false, false), true),
+            (Opcode::JN, flags(false, false, true, false), false),
+            (Opcode::JO, flags(false, false, false, true), true),
+            (Opcode::JO, flags(false, false, true, false), false),
+        ];
+        for (opcode, state, taken) in jumps {
+            let mut test_vm = VM::new();
+            test_vm.flags = state;
+            test_vm.program = vec![opcode.into(), 0, 0, 0, 6, 0, 0];
+            test_vm.run().unwrap();
+            assert_eq!(test_vm.pc, if taken { 7 } else { 6 }, "{:?}", opcode);
+        }
+    }
+    #[test]
+    fn test_float_registers() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[1] = 7.5;
+        test_vm.float_registers[2] = 2.0;
+        test_vm.program = vec![
+            46, 1, 2, 3, // addf $f1 $f2 $f3
+            47, 1, 2, 4, // subf $f1 $f2 $f4
+            48, 1, 2, 5, // mulf $f1 $f2 $f5
+            49, 1, 2, 6, // divf $f1 $f2 $f6
+            49, 1, 0, 7, // divf $f1 $f0 $f7
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(
+            test_vm.float_registers[3..8],
+            [9.5, 5.5, 15.0, 3.75, f64::INFINITY]
+        );
+
+        test_vm.program = vec![51, 2, 1, 50, 1, 2];
+        test_vm.pc = 0;
+        test_vm.step();
+        assert!(test_vm.equal_flag);
+        test_vm.step();
+        assert!(!test_vm.equal_flag);
+
+        let mut program = vec![45, 0];
+        program.extend((-2.75f64).to_bits().to_be_bytes());
+        program.extend([53, 0, 1, 52, 1, 9]);
+        test_vm.program = program;
+        test_vm.pc = 0;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[0], -2.75);
+        assert_eq!(test_vm.registers[1], -2);
+        assert_eq!(test_vm.float_registers[9], -2.0);
+
+        test_vm.program = vec![46, 1, 2, 32];
+        test_vm.pc = 0;
+        assert!(matches!(
+            test_vm.run(),
+            Err(VmError::BadRegister { register: 32, .. })
+        ));
+    }
+    #[test]
+    fn test_long_registers() {
+        let mut test_vm = VM::new();
+        test_vm.long_registers[1] = 3_000_000_000;
+        test_vm.long_registers[2] = -7;
+        test_vm.program = vec![
+            55, 1, 1, 3, // addl $l1 $l1 $l3
+            56, 2, 1, 4, // subl $l2 $l1 $l4
+            57, 1, 2, 5, // mull $l1 $l2 $l5
+            58, 1, 2, 6, // divl $l1 $l2 $l6
+            62, 3, 7, // ltoi $l3 $7
+            61, 7, 8, // itol $7 $l8
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(
+            test_vm.long_registers[3..9],
+            [
+                6_000_000_000,
+                -3_000_000_007,
+                -21_000_000_000,
+                -428_571_428,
+                0,
+                1_705_032_704
+            ]
+        );
+        assert_eq!(test_vm.registers[7], 1_705_032_704);
+        assert_eq!(test_vm.registers[1..7], [0; 6]);
+
+        test_vm.program = vec![60, 2, 1, 59, 1, 2];
+        test_vm.pc = 0;
+        test_vm.step();
+        assert!(test_vm.equal_flag);
+        test_vm.step();
+        assert!(!test_vm.equal_flag);
+
+        // Flags follow the whole 64-bit result, not its low word.
+        test_vm.long_registers[13] = 1 << 32;
+        test_vm.long_registers[14] = 3 << 32;
+        test_vm.program = vec![55, 13, 13, 15, 56, 13, 14, 15, 56, 13, 13, 15];
+        test_vm.pc = 0;
+        test_vm.step();
+        assert_eq!(test_vm.flags, flags(false, false, false, false));
+        test_vm.step();
+        assert_eq!(test_vm.long_registers[15], -2 << 32);
+        assert_eq!(test_vm.flags, flags(false, true, true, false));
+        test_vm.step();
+        assert_eq!(test_vm.flags, flags(true, false, false, false));
+
+        test_vm.long_registers[9] = i64::MAX;
+        test_vm.long_registers[10] = 1;
+        test_vm.program = vec![55, 9, 10, 11, 58, 1, 0, 12];
+        test_vm.pc = 0;
+        test_vm.overflow_mode = OverflowMode::Trapping;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::ArithmeticOverflow {
+                pc: 0,
+                opcode: Opcode::ADDL
+            })
+        );
+        assert_eq!(test_vm.flags, flags(false, true, false, true));
+        test_vm.overflow_mode = OverflowMode::Wrapping;
+        test_vm.pc = 0;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::DivideByZero {
+                pc: 4,
+                opcode: Opcode::DIVL
+            })
+        );
+        assert_eq!(test_vm.long_registers[11], i64::MIN);
+    }
+    /// `Write` handle whose bytes the test keeps access to.
+    #[derive(Clone, Default)]
+    struct Console(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Console {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn console_vm(input: &'static str) -> (VM, Console) {
+        let console = Console::default();
+        let mut test_vm = VM::new();
+        test_vm.input = Box::new(input.as_bytes());
+        test_vm.output = Box::new(console.clone());
+        (test_vm, console)
+    }
+    #[test]
+    fn test_syscalls() {
+        let source = "
+            .data
+            prompt: .asciiz \"name? \"
+            hello: .asciiz \"hi \"
+            .code
+                  load $1 2
+                  load $2 @prompt
+                  syscall
+                  load $1 3
+                  syscall
+                  add $1 $zero $4 ; keep the line
+                  push $2
+                  load $1 2
+                  load $2 @hello
+                  syscall
+                  add $4 $zero $2
+                  syscall
+                  load $1 1
+                  pop $2
+                  syscall
+                  load $1 0
+                  load $2 7
+                  syscall
+                  halt
+        ";
+        let program = crate::assembler::Assembler::new().assemble(source).unwrap();
+        let (mut test_vm, console) = console_vm("pecet\r\nrest\n");
+        test_vm.load_program(program);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Exited(7)));
+        assert_eq!(console.0.lock().unwrap().as_slice(), b"name? hi pecet5");
+        assert_eq!(&test_vm.heap[..6], b"pecet\0");
+    }
+    #[test]
+    fn test_read_line_at_end_of_input() {
+        let (mut test_vm, _) = console_vm("last");
+        test_vm.registers[1] = 3;
+        test_vm.program = vec![66, 66];
+        test_vm.step();
+        assert_eq!(test_vm.registers[2], 4);
+        assert_eq!(test_vm.heap, b"last\0\0\0\0");
+        test_vm.registers[1] = 3;
+        test_vm.step();
+        assert_eq!(test_vm.registers[2], -1);
+    }
+    #[test]
+    fn test_syscall_faults() {
+        let (mut test_vm, _) = console_vm("");
+        test_vm.registers[1] = 9;
+        test_vm.program = vec![66];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::UnknownSyscall {
+                pc: 0,
+                opcode: Opcode::SYSCALL,
+                number: 9
+            })
+        );
+
+        // A string without its terminator runs off the read-only data.
+        test_vm.ro_data = b"abc".to_vec();
+        test_vm.registers[1] = 2;
+        test_vm.pc = 0;
+        assert!(matches!(
+            test_vm.run(),
+            Err(VmError::MemoryFault { address: 3, .. })
+        ));
+    }
+    #[test]
+    fn test_natives() {
+        let log = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let mut test_vm = VM::new();
+        let seen = log.clone();
+        assert_eq!(
+            test_vm.register_native("sum", |_, args| Ok(args.iter().sum())),
+            Ok(0)
+        );
+        assert_eq!(
+            test_vm.register_native("log", move |vm, args| {
+                seen.lock().unwrap().push((args.to_vec(), vm.pc));
+                vm.registers[9] = -1;
+                Ok(0)
+            }),
+            Ok(1)
+        );
+        assert_eq!(
+            test_vm.register_native("fail", |_, _| Err("no".into())),
+            Ok(2)
+        );
+        assert_eq!(test_vm.native_names(), ["sum", "log", "fail"]);
+
+        test_vm.registers[2..5].copy_from_slice(&[4, 5, 6]);
+        test_vm.program = vec![
+            67, 0, 0, 2, 0, 3, // calln 0 $2 3
+            67, 0, 1, 3, 0, 2, // calln 1 $3 2
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2..5], [15, 0, 6]);
+        assert_eq!(test_vm.registers[9], -1);
+        assert_eq!(*log.lock().unwrap(), [(vec![5, 6], 12)]);
+
+        test_vm.program = vec![67, 0, 2, 1, 0, 0];
+        test_vm.pc = 0;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::NativeFailed {
+                pc: 0,
+                opcode: Opcode::CALLN,
+                name: "fail".to_string(),
+                message: "no".to_string()
+            })
+        );
+        test_vm.program = vec![67, 0, 3, 1, 0, 0];
+        test_vm.pc = 0;
+        assert!(matches!(
+            test_vm.run(),
+            Err(VmError::UnknownNative { index: 3, .. })
+        ));
+        for (first, count) in [(30, 3i16), (2, -1)] {
+            test_vm.program = vec![67, 0, 0, first];
+            test_vm.program.extend(count.to_be_bytes());
+            test_vm.pc = 0;
+            assert_eq!(
+                test_vm.run(),
+                Err(VmError::BadArgumentCount {
+                    pc: 0,
+                    opcode: Opcode::CALLN,
+                    first,
+                    count
+                })
+            );
+        }
+
+        // Replacing a function keeps its index.
+        assert_eq!(test_vm.register_native("sum", |_, _| Ok(42)), Ok(0));
+        test_vm.program = vec![67, 0, 0, 7, 0, 0];
+        test_vm.pc = 0;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[7], 42);
+    }
+    #[test]
+    fn test_native_running_bytecode() {
+        // The native calls back into the program, which calls it again.
+        let mut test_vm = VM::new();
+        test_vm
+            .register_native("nested", |vm, _| {
+                vm.pc = 0;
+                vm.run().map(|_| 1).map_err(|error| error.to_string())
+            })
+            .unwrap();
+        test_vm.program = vec![67, 0, 0, 1, 0, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::NativeFailed {
+                pc: 0,
+                opcode: Opcode::CALLN,
+                name: "nested".to_string(),
+                message: "CALLN at pc 0 called native `nested` which failed: it is already running"
+                    .to_string()
+            })
+        );
+    }
+    #[test]
+    fn test_native_table_full() {
+        let mut test_vm = VM::new();
+        assert_eq!(test_vm.register_native("native0", |_, _| Ok(0)), Ok(0));
+        // Registering the rest one by one would compare every name each time.
+        test_vm.natives.extend((1..=u16::MAX).map(|index| Native {
+            name: format!("native{}", index),
+            function: None,
+        }));
+        assert_eq!(
+            test_vm.register_native("native65535", |_, _| Ok(0)),
+            Ok(u16::MAX)
+        );
+        let error = test_vm
+            .register_native("one_more", |_, _| Ok(0))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot register native `one_more`, all 65536 indexes are taken"
+        );
+        // Replacing an existing name still works with a full table.
+        assert_eq!(test_vm.register_native("native7", |_, _| Ok(1)), Ok(7));
     }
 }